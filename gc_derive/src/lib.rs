@@ -17,42 +17,100 @@ macro_rules! bail {
 #[proc_macro_derive(Trace, attributes(trace))]
 pub fn derive_trace(tokens: TokenStream) -> TokenStream {
     let syn::DeriveInput {
+        attrs,
         ident,
-        generics,
+        mut generics,
         data,
         ..
     } = syn::parse_macro_input!(tokens as syn::DeriveInput);
 
+    let container_attr = match parse_container_attr(&attrs) {
+        Ok(attr) => attr,
+        Err(e) => return e.into_compile_error().into(),
+    };
+    // `Drop` impls may not add bounds, so this has to be taken
+    // before the `Trace` bounds are added below.
+    let drop_impl = {
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        match container_attr {
+            // Sweep drops dead objects in no particular order, so a `Drop` impl
+            // could observe an object which was already freed through one of its
+            // `Member` fields. Any `Drop` impl conflicts with the blanket impl here.
+            None => quote! {
+                const _: () = {
+                    trait MustNotImplDrop {}
+                    #[allow(drop_bounds)]
+                    impl<T: ::core::ops::Drop> MustNotImplDrop for T {}
+                    impl #impl_generics MustNotImplDrop for #ident #ty_generics #where_clause {}
+                };
+            },
+            Some(ContainerAttr::UnsafeDrop) => quote!(),
+            Some(ContainerAttr::Finalize) => quote! {
+                impl #impl_generics ::core::ops::Drop for #ident #ty_generics #where_clause {
+                    fn drop(&mut self) {
+                        ::gc::Finalize::finalize(self);
+                    }
+                }
+            },
+        }
+    };
+
     let body = match data {
         syn::Data::Struct(syn::DataStruct { fields, .. }) => {
-            let fields = fields
-                .into_iter()
-                .map(|syn::Field { ident, .. }| quote!(self.#ident.trace()));
+            let mut body = vec![];
+            for (i, field) in fields.into_iter().enumerate() {
+                let member = match &field.ident {
+                    Some(ident) => syn::Member::Named(ident.clone()),
+                    None => syn::Member::Unnamed(syn::Index::from(i)),
+                };
+                match trace_field(&field, quote!(&self.#member)) {
+                    Ok(tokens) => body.push(tokens),
+                    Err(e) => return e.into_compile_error().into(),
+                }
+            }
+            quote! {
+                #(#body)*
+            }
+        }
+        syn::Data::Enum(syn::DataEnum { variants, .. }) if variants.is_empty() => {
             quote! {
-                #(#fields;)*
+                match *self {}
             }
         }
         syn::Data::Enum(syn::DataEnum { variants, .. }) => {
-            let variants = variants
-                .into_iter()
-                .map(|syn::Variant { ident, fields, .. }| match fields {
-                    syn::Fields::Named(syn::FieldsNamed { named, .. }) => {
-                        let traced = named
-                            .iter()
-                            .map(|syn::Field { ident, .. }| quote!(#ident.trace()));
+            let mut arms = vec![];
+            for syn::Variant { ident, fields, .. } in variants {
+                // fields are never bound by their own names, which
+                // could shadow `tracer` or the path of a `with` attribute
+                let bindings = (0..fields.len())
+                    .map(|i| quote::format_ident!("__v{i}"))
+                    .collect::<Vec<_>>();
+                let mut body = vec![];
+                for (field, binding) in fields.iter().zip(&bindings) {
+                    match trace_field(field, quote!(#binding)) {
+                        Ok(tokens) => body.push(tokens),
+                        Err(e) => return e.into_compile_error().into(),
+                    }
+                }
+                arms.push(match fields {
+                    syn::Fields::Named(_) => {
+                        let names = fields.iter().map(|field| &field.ident);
                         quote! {
-                            Self::#ident { #named } => {
-                                #(#traced;),*
-                            }
+                            Self::#ident { #(#names: #bindings),* } => { #(#body)* }
                         }
                     }
-                    syn::Fields::Unnamed(_) => todo!(),
-                    syn::Fields::Unit => todo!(),
+                    syn::Fields::Unnamed(_) => quote! {
+                        Self::#ident ( #(#bindings),* ) => { #(#body)* }
+                    },
+                    syn::Fields::Unit => quote! {
+                        Self::#ident => {}
+                    },
                 });
+            }
 
             quote! {
                 match self {
-                    #(#variants)*
+                    #(#arms)*
                 }
             }
         }
@@ -61,10 +119,7 @@ pub fn derive_trace(tokens: TokenStream) -> TokenStream {
         }
     };
 
-    let mut bounds = generics.clone();
-    let params = generics;
-
-    for type_param in bounds.type_params_mut() {
+    for type_param in generics.type_params_mut() {
         type_param
             .bounds
             .push(syn::TypeParamBound::Trait(syn::TraitBound {
@@ -87,12 +142,99 @@ pub fn derive_trace(tokens: TokenStream) -> TokenStream {
             }));
     }
 
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
     quote! {
-        unsafe impl #bounds ::gc::Trace for #ident #params {
-            unsafe fn trace(&self) {
+        unsafe impl #impl_generics ::gc::Trace for #ident #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            unsafe fn trace(&self, tracer: &mut ::gc::Tracer) {
                 #body
             }
         }
+
+        #drop_impl
     }
     .into()
 }
+
+enum ContainerAttr {
+    /// `#[trace(unsafe_drop)]`: the type may implement `Drop`.
+    UnsafeDrop,
+    /// `#[trace(finalize)]`: the type implements `Finalize`, which is called from `Drop`.
+    Finalize,
+}
+
+fn parse_container_attr(attrs: &[syn::Attribute]) -> syn::Result<Option<ContainerAttr>> {
+    let mut result = None;
+    for attr in attrs {
+        if !attr.path().is_ident("trace") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if result.is_some() {
+                return Err(meta.error("only one `trace` attribute is allowed per type"));
+            }
+            if meta.path.is_ident("unsafe_drop") {
+                result = Some(ContainerAttr::UnsafeDrop);
+            } else if meta.path.is_ident("finalize") {
+                result = Some(ContainerAttr::Finalize);
+            } else {
+                return Err(
+                    meta.error("unknown `trace` attribute, expected `unsafe_drop` or `finalize`")
+                );
+            }
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+enum FieldAttr {
+    /// `#[trace(skip)]`: the field type implements `NoTrace`.
+    Skip,
+    /// `#[trace(unsafe_ignore)]`: the field is not traced, and nothing is checked.
+    UnsafeIgnore,
+    /// `#[trace(with = path)]`: the field is traced by calling `path(&field, tracer)`.
+    With(syn::Path),
+}
+
+fn parse_field_attr(field: &syn::Field) -> syn::Result<Option<FieldAttr>> {
+    let mut result = None;
+    for attr in &field.attrs {
+        if !attr.path().is_ident("trace") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if result.is_some() {
+                return Err(meta.error("only one `trace` attribute is allowed per field"));
+            }
+            if meta.path.is_ident("skip") {
+                result = Some(FieldAttr::Skip);
+            } else if meta.path.is_ident("unsafe_ignore") {
+                result = Some(FieldAttr::UnsafeIgnore);
+            } else if meta.path.is_ident("with") {
+                result = Some(FieldAttr::With(meta.value()?.parse()?));
+            } else {
+                return Err(meta.error(
+                    "unknown `trace` attribute, expected `skip`, `unsafe_ignore` or `with = path`",
+                ));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(result)
+}
+
+/// Emit the statement which traces `field`, given an expression
+/// which evaluates to a reference to it.
+fn trace_field(
+    field: &syn::Field,
+    place: proc_macro2::TokenStream,
+) -> syn::Result<proc_macro2::TokenStream> {
+    Ok(match parse_field_attr(field)? {
+        None => quote!(::gc::Trace::trace(#place, tracer);),
+        Some(FieldAttr::Skip) => quote!(::gc::__assert_no_trace(#place);),
+        Some(FieldAttr::UnsafeIgnore) => quote!(),
+        Some(FieldAttr::With(path)) => quote!(#path(#place, tracer);),
+    })
+}
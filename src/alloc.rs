@@ -1,64 +1,467 @@
+use crate::ephemeron::Ephemeron;
+use crate::gc::AllocError;
+use crate::gc::Event;
+use crate::gc::HeapId;
 use crate::gc::Trace;
-use std::alloc::dealloc;
+use crate::gc::Tracer;
+use crate::handle::Scope;
+use crate::large::mapping_size;
+use crate::large::LargeObjectSpace;
+use crate::page::cell_size;
+use crate::page::Heap;
+use crate::page::MAX_CELL_SIZE;
 use std::alloc::Layout;
+use std::any::TypeId;
 use std::cell::Cell;
 use std::cell::UnsafeCell;
+use std::cmp;
+use std::collections::VecDeque;
 use std::mem::transmute;
 use std::ptr::addr_of;
 use std::ptr::addr_of_mut;
-use std::ptr::null_mut;
 
 pub struct Allocator {
-    pub(crate) head: Cell<*mut GcCell<Data>>,
+    /// Id of the `Gc` which owns this allocator.
+    pub(crate) id: HeapId,
+
+    /// Pages holding all objects below the large object threshold, of both generations.
+    pub(crate) heap: UnsafeCell<Heap>,
+
+    /// Objects above the large object threshold.
+    pub(crate) large: UnsafeCell<LargeObjectSpace>,
+
     pub(crate) config: Config,
+
+    /// `true` while an incremental marking cycle is in progress.
+    pub(crate) marking: Cell<bool>,
+
+    /// Objects which are marked, but whose fields have not been traced yet.
+    ///
+    /// Together with the mark bit, this forms the usual tri-color abstraction:
+    /// - white: not marked
+    /// - grey: marked, and present in this list
+    /// - black: marked, and no longer present in this list
+    ///
+    /// Marking only ever works through this list, so the depth of the
+    /// object graph does not affect native stack usage.
+    pub(crate) grey: UnsafeCell<MarkStack>,
+
+    /// Number of bytes currently allocated, including object headers
+    /// and the unused part of each cell.
+    pub(crate) bytes: Cell<usize>,
+
+    /// Number of bytes in the large object space, which are also counted in `bytes`.
+    pub(crate) large_bytes: Cell<usize>,
+
+    /// Number of objects currently allocated.
+    pub(crate) objects: Cell<usize>,
+
+    /// Number of objects in the large object space, which are also counted in `objects`.
+    pub(crate) large_objects: Cell<usize>,
+
+    /// Number of objects allocated since the heap was created.
+    pub(crate) total_objects: Cell<u64>,
+
+    /// Number of bytes allocated since the heap was created.
+    pub(crate) total_bytes: Cell<u64>,
+
+    /// Number of completed full collections.
+    pub(crate) collections: Cell<u64>,
+
+    /// Number of completed minor collections.
+    pub(crate) minor_collections: Cell<u64>,
+
+    /// Once `bytes` exceeds this, allocation triggers a GC step.
+    pub(crate) threshold: Cell<usize>,
+
+    /// Number of bytes allocated in the nursery since the last minor collection.
+    pub(crate) young_bytes: Cell<usize>,
+
+    /// `true` while a minor collection is in progress.
+    pub(crate) minor: Cell<bool>,
+
+    /// Old objects which may refer to young ones.
+    ///
+    /// Every surviving young object is promoted by a minor collection, so
+    /// the only way for an old object to refer to a young one is through a
+    /// store into it. The write barrier remembers old objects which receive
+    /// a young reference, and the next minor collection traces their fields.
+    pub(crate) remembered: UnsafeCell<Vec<*mut GcCell<Data>>>,
+
+    /// Objects with `WeakMember`s, found while tracing the current cycle.
+    ///
+    /// Only the objects are recorded, because a `WeakMember` stored outside
+    /// of its object's own memory, such as in a `Vec`, may be freed between
+    /// incremental steps. The objects themselves are marked, so they stay in
+    /// place until the cycle ends, when they are traced again to clear their
+    /// references to dead objects.
+    pub(crate) weak_holders: UnsafeCell<Vec<*mut GcCell<Data>>>,
+
+    /// Ephemeron tables found while tracing the current cycle.
+    pub(crate) ephemerons: UnsafeCell<Vec<*const dyn Ephemeron>>,
+
+    /// Finalizers registered for objects which have not died yet.
+    ///
+    /// These do not keep their objects alive.
+    pub(crate) finalizers: UnsafeCell<Vec<Finalizer>>,
+
+    /// Finalizers of dead objects, waiting to be run.
+    ///
+    /// Their objects are roots until the finalizer runs.
+    pub(crate) finalization_queue: UnsafeCell<VecDeque<Finalizer>>,
+
+    /// `true` while the finalization queue is being drained.
+    pub(crate) finalizing: Cell<bool>,
+
+    /// Callbacks registered with `Gc::on_event`.
+    pub(crate) listeners: UnsafeCell<Vec<Listener>>,
+}
+
+type FinalizerFn = Box<dyn FnOnce(&mut Scope<'_>, *mut GcCell<Data>)>;
+
+pub(crate) type Listener = Box<dyn FnMut(&Event)>;
+
+/// A stack of grey objects, stored in fixed-size chunks.
+///
+/// Growing a single `Vec` copies the whole stack every time it runs out
+/// of capacity, and keeps the memory until the heap is dropped. Chunks are
+/// allocated as needed, and freed once they are empty.
+pub(crate) struct MarkStack {
+    chunk_size: usize,
+
+    /// Every chunk is non-empty, and the last one is the top of the stack.
+    chunks: Vec<Vec<*mut GcCell<Data>>>,
+
+    /// The most recently emptied chunk, kept so that a stack which keeps
+    /// crossing the same chunk boundary does not allocate every time.
+    spare: Option<Vec<*mut GcCell<Data>>>,
+}
+
+impl MarkStack {
+    pub(crate) fn new(chunk_size: usize) -> Self {
+        Self {
+            chunk_size,
+            chunks: Vec::new(),
+            spare: None,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn push(&mut self, ptr: *mut GcCell<Data>) {
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.len() < self.chunk_size => chunk.push(ptr),
+            _ => {
+                let mut chunk = self
+                    .spare
+                    .take()
+                    .unwrap_or_else(|| Vec::with_capacity(self.chunk_size));
+                chunk.push(ptr);
+                self.chunks.push(chunk);
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn pop(&mut self) -> Option<*mut GcCell<Data>> {
+        let chunk = self.chunks.last_mut()?;
+        let ptr = chunk.pop();
+        if chunk.is_empty() {
+            self.spare = self.chunks.pop();
+        }
+        ptr
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+}
+
+/// A finalizer registered for the object in `cell`.
+pub(crate) struct Finalizer {
+    pub(crate) cell: *mut GcCell<Data>,
+    pub(crate) run: FinalizerFn,
 }
 
 impl Allocator {
     #[inline]
-    pub(crate) fn new(config: Config) -> Self {
+    pub(crate) fn new(config: Config, id: HeapId) -> Self {
         Self {
-            head: Cell::new(null_mut()),
+            id,
+            heap: UnsafeCell::new(Heap::default()),
+            large: UnsafeCell::new(LargeObjectSpace::default()),
             config,
+            marking: Cell::new(false),
+            grey: UnsafeCell::new(MarkStack::new(config.mark_chunk_size)),
+            bytes: Cell::new(0),
+            large_bytes: Cell::new(0),
+            objects: Cell::new(0),
+            large_objects: Cell::new(0),
+            total_objects: Cell::new(0),
+            total_bytes: Cell::new(0),
+            collections: Cell::new(0),
+            minor_collections: Cell::new(0),
+            threshold: Cell::new(config.min_heap),
+            young_bytes: Cell::new(0),
+            minor: Cell::new(false),
+            remembered: UnsafeCell::new(Vec::new()),
+            weak_holders: UnsafeCell::new(Vec::new()),
+            ephemerons: UnsafeCell::new(Vec::new()),
+            finalizers: UnsafeCell::new(Vec::new()),
+            finalization_queue: UnsafeCell::new(VecDeque::new()),
+            finalizing: Cell::new(false),
+            listeners: UnsafeCell::new(Vec::new()),
         }
     }
 
     #[inline]
     pub(crate) fn alloc<T: Trace>(&self, data: T) -> *mut GcCell<T> {
-        let ptr = Box::into_raw(Box::new(GcCell {
-            header: GcHeader {
-                prev: UnsafeCell::new(self.head.get()),
-                vt: Vt::get::<T>(),
-                mark: UnsafeCell::new(false),
-            },
-            data,
-        }));
-        debug!("alloc {ptr:p}");
-
-        // TODO: maybe use `next` pointers instead of `prev`,
-        // so the head doesn't need to be updated constantly?
-        self.head.set(GcCell::erase(ptr));
+        let layout = Layout::new::<GcCell<T>>();
+        let small = if layout.size() <= self.config.large_object_threshold {
+            unsafe { (*self.heap.get()).alloc(layout) }
+        } else {
+            None
+        };
+        let (cell, size) = small.unwrap_or_else(|| {
+            let (cell, size) = unsafe { (*self.large.get()).alloc(layout) };
+            self.large_bytes.set(self.large_bytes.get() + size);
+            self.large_objects.set(self.large_objects.get() + 1);
+            (cell, size)
+        });
+        let ptr = cell as *mut GcCell<T>;
+        unsafe {
+            ptr.write(GcCell {
+                header: GcHeader {
+                    vt: Vt::get::<T>(),
+                    mark: UnsafeCell::new(false),
+                    old: UnsafeCell::new(false),
+                    remembered: UnsafeCell::new(false),
+                },
+                data,
+            });
+        }
+        trace!(ptr = ptr; "alloc");
+
+        self.bytes.set(self.bytes.get() + size);
+        self.young_bytes.set(self.young_bytes.get() + size);
+        self.objects.set(self.objects.get() + 1);
+        self.total_objects.set(self.total_objects.get() + 1);
+        self.total_bytes.set(self.total_bytes.get() + size as u64);
         ptr
     }
+
+    /// Check that allocating a `T` would not grow the heap beyond the heap limit.
+    #[inline]
+    pub(crate) fn check_limit<T: Trace>(&self) -> Result<(), AllocError> {
+        let layout = Layout::new::<GcCell<T>>();
+        let small = if layout.size() <= self.config.large_object_threshold {
+            cell_size(layout)
+        } else {
+            None
+        };
+        let size = small.unwrap_or_else(|| mapping_size(layout));
+        let limit = self.config.heap_limit;
+        if self.bytes.get().saturating_add(size) > limit {
+            return Err(AllocError { size, limit });
+        }
+        Ok(())
+    }
+
+    /// Turn a white object grey.
+    ///
+    /// ## Safety
+    /// `ptr` must point to a live object.
+    #[inline]
+    pub(crate) unsafe fn shade(&self, ptr: *mut GcCell<Data>) {
+        if GcCell::is_marked(ptr) {
+            return;
+        }
+        if self.minor.get() && GcCell::is_old(ptr) {
+            // minor collections only trace the nursery
+            return;
+        }
+
+        trace!(ptr = ptr; "shade");
+        GcCell::set_mark(ptr, true);
+        (*self.grey.get()).push(ptr);
+    }
+
+    /// Write barrier.
+    ///
+    /// Must be called whenever a reference to `value` is stored into `holder`.
+    ///
+    /// While marking is in progress, this is an insertion (Dijkstra) barrier:
+    /// The stored object is shaded, so that a black object never points to
+    /// a white one.
+    ///
+    /// An old `holder` which now refers to a young `value` is added to the
+    /// remembered set.
+    ///
+    /// ## Safety
+    /// `holder` and `value` must point to live objects.
+    #[inline]
+    pub(crate) unsafe fn write_barrier(&self, holder: *mut GcCell<Data>, value: *mut GcCell<Data>) {
+        if self.marking.get() {
+            self.shade(value);
+        }
+        if !GcCell::is_old(value) {
+            self.remember(holder);
+        }
+    }
+
+    /// Trace the fields of `ptr` during the next minor collection.
+    ///
+    /// Used when references to young objects may have been written into `ptr`.
+    /// Young objects are traced by every minor collection anyway, so only
+    /// old ones are remembered.
+    ///
+    /// ## Safety
+    /// `ptr` must point to a live object.
+    #[inline]
+    pub(crate) unsafe fn remember(&self, ptr: *mut GcCell<Data>) {
+        if GcCell::is_old(ptr) && !GcCell::is_remembered(ptr) {
+            trace!(ptr = ptr; "remember");
+            GcCell::set_remembered(ptr, true);
+            (*self.remembered.get()).push(ptr);
+        }
+    }
+
+    /// Whether allocating should perform a minor collection.
+    #[inline]
+    pub(crate) fn should_collect_young(&self) -> bool {
+        self.config.nursery_size > 0 && self.young_bytes.get() > self.config.nursery_size
+    }
+
+    /// Whether `ptr` will be freed at the end of the current collection.
+    ///
+    /// Only meaningful once marking is done.
+    #[inline]
+    pub(crate) unsafe fn is_dead(&self, ptr: *mut GcCell<Data>) -> bool {
+        if GcCell::is_marked(ptr) {
+            return false;
+        }
+        // minor collections never mark the old generation
+        !(self.minor.get() && GcCell::is_old(ptr))
+    }
+
+    /// Panics unless `ptr` points to a live `T` in this heap.
+    ///
+    /// A `Member` can be kept until after its object was freed, so the
+    /// references in new objects are checked, before they can be reached
+    /// through a rooted parent.
+    #[inline]
+    pub(crate) fn check_live<T: Trace>(&self, ptr: *mut GcCell<T>) {
+        let ptr = GcCell::erase(ptr);
+        let live = unsafe {
+            ((*self.heap.get()).contains(ptr) || (*self.large.get()).contains(ptr))
+                && GcCell::is::<T>(ptr)
+        };
+        assert!(
+            live,
+            "found a reference to an object which is no longer live"
+        );
+    }
+
+    /// Whether allocating should perform a GC step.
+    ///
+    /// Once a cycle has been started, it must keep making progress,
+    /// so every allocation performs a step until the cycle is done.
+    #[inline]
+    pub(crate) fn should_collect(&self) -> bool {
+        self.marking.get() || self.bytes.get() > self.threshold.get()
+    }
+
+    /// Compute the next collection threshold based on the amount of bytes which
+    /// survived the last cycle.
+    pub(crate) fn update_threshold(&self) {
+        let next = (self.bytes.get() as f64 * self.config.growth_factor) as usize;
+        self.threshold.set(cmp::max(next, self.config.min_heap));
+        debug!(bytes = self.bytes.get(), threshold = self.threshold.get(); "update threshold");
+    }
+
+    /// Pop the next grey object to be traced.
+    #[inline]
+    pub(crate) fn pop_grey(&self) -> Option<*mut GcCell<Data>> {
+        unsafe { (*self.grey.get()).pop() }
+    }
+}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        for page in self.heap.get_mut().pages_mut() {
+            unsafe { page.retain(|_| false) };
+        }
+        unsafe { self.large.get_mut().retain(|_| false) };
+    }
 }
 
 #[derive(Clone, Copy)]
 pub struct Config {
     pub stress: bool,
+
+    /// Maximum number of objects traced by a single incremental step.
+    pub mark_slice: usize,
+
+    /// The heap may grow by this factor relative to the amount of bytes
+    /// which survived the last cycle before another one is triggered.
+    pub growth_factor: f64,
+
+    /// Allocation never triggers a GC step while the heap is smaller than this.
+    pub min_heap: usize,
+
+    /// Allocation triggers a minor collection once this many bytes
+    /// have been allocated in the nursery. `0` disables minor collections.
+    pub nursery_size: usize,
+
+    /// Number of objects in each chunk of the mark stack.
+    pub mark_chunk_size: usize,
+
+    /// Objects larger than this many bytes, including the header, are
+    /// allocated in the large object space. Objects which do not fit into
+    /// the largest size class always are.
+    pub large_object_threshold: usize,
+
+    /// Allocation fails once it would grow the heap beyond this many bytes.
+    pub heap_limit: usize,
+
+    /// Check that all references are still valid after every collection.
+    pub verify: bool,
 }
 
 #[allow(clippy::derivable_impls)]
 impl Default for Config {
     #[cfg(test)]
     fn default() -> Self {
-        Self { stress: true }
+        Self {
+            stress: true,
+            mark_slice: 1024,
+            growth_factor: 2.0,
+            min_heap: 1024 * 1024,
+            nursery_size: 256 * 1024,
+            mark_chunk_size: 1024,
+            large_object_threshold: MAX_CELL_SIZE,
+            heap_limit: usize::MAX,
+            verify: false,
+        }
     }
 
     #[cfg(not(test))]
     fn default() -> Self {
-        Self { stress: false }
+        Self {
+            stress: false,
+            mark_slice: 1024,
+            growth_factor: 2.0,
+            min_heap: 1024 * 1024,
+            nursery_size: 256 * 1024,
+            mark_chunk_size: 1024,
+            large_object_threshold: MAX_CELL_SIZE,
+            heap_limit: usize::MAX,
+            verify: false,
+        }
     }
 }
 
+#[repr(C)]
 pub struct GcCell<T: ?Sized> {
     header: GcHeader,
     data: T,
@@ -76,40 +479,44 @@ impl<T: Trace> GcCell<T> {
 }
 
 impl GcCell<Data> {
-    pub(crate) unsafe fn free(this: *mut Self) {
+    /// Drop the contents of `this`, leaving the cell to be reused.
+    pub(crate) unsafe fn drop_data(this: *mut Self) {
         let vt = (*this).header.vt;
         let data = addr_of_mut!((*this).data);
         let drop_in_place = addr_of!((*vt).drop_in_place).read();
-        let size = addr_of!((*vt).size).read();
-        let align = addr_of!((*vt).align).read();
-        let layout = Layout::new::<GcHeader>()
-            .extend(Layout::from_size_align(size, align).unwrap())
-            .unwrap()
-            .0
-            .pad_to_align();
 
-        debug!("free {this:p} {layout:?}");
+        trace!(ptr = this; "free");
 
         drop_in_place(data);
-        dealloc(this as *mut u8, layout)
     }
 
+    /// Name of the type of the object in `this`.
     #[inline]
-    pub(crate) unsafe fn trace(this: *const Self) {
-        if Self::is_marked(this) {
-            debug!("already marked {:p}", this);
-            return;
-        }
+    pub(crate) unsafe fn type_name(this: *const Self) -> &'static str {
+        let vt = (*this).header.vt;
+        let type_name = addr_of!((*vt).type_name).read();
+        type_name()
+    }
+
+    /// Whether the object in `this` is a `T`.
+    #[inline]
+    pub(crate) unsafe fn is<T: Trace>(this: *const Self) -> bool {
+        let vt = (*this).header.vt;
+        let type_id = addr_of!((*vt).type_id).read();
+        type_id() == TypeId::of::<T>()
+    }
 
-        debug!("trace {:p}", this);
-        Self::set_mark(this, true);
+    /// Trace through the fields of `this` using `tracer`.
+    #[inline]
+    pub(crate) unsafe fn trace(this: *const Self, tracer: &mut Tracer) {
+        trace!(ptr = this; "trace");
 
         let vt = (*this).header.vt;
         let data = addr_of!((*this).data) as *const Data;
 
         {
             let trace = addr_of!((*vt).trace).read();
-            trace(data);
+            trace(data, tracer);
         }
     }
 
@@ -124,20 +531,36 @@ impl GcCell<Data> {
     }
 
     #[inline]
-    pub(crate) unsafe fn set_prev(this: *const Self, ptr: *mut GcCell<Data>) {
-        (*this).header.prev.get().write(ptr);
+    pub(crate) unsafe fn set_old(this: *const Self, v: bool) {
+        (*this).header.old.get().write(v);
+    }
+
+    #[inline]
+    pub(crate) unsafe fn is_old(this: *const Self) -> bool {
+        (*this).header.old.get().read()
+    }
+
+    #[inline]
+    pub(crate) unsafe fn set_remembered(this: *const Self, v: bool) {
+        (*this).header.remembered.get().write(v);
     }
 
     #[inline]
-    pub(crate) unsafe fn get_prev(this: *const Self) -> *mut GcCell<Data> {
-        (*this).header.prev.get().read()
+    pub(crate) unsafe fn is_remembered(this: *const Self) -> bool {
+        (*this).header.remembered.get().read()
     }
 }
 
+/// `vt` must be the first field, because free cells are
+/// told apart from objects by a null pointer in its place.
+#[repr(C)]
 struct GcHeader {
-    prev: UnsafeCell<*mut GcCell<Data>>,
     vt: *mut Vt,
     mark: UnsafeCell<bool>,
+    /// Set once the object survives a minor collection.
+    old: UnsafeCell<bool>,
+    /// Set while the object is in the remembered set.
+    remembered: UnsafeCell<bool>,
 }
 
 pub type Data = ();
@@ -145,9 +568,9 @@ pub type Data = ();
 #[repr(C)]
 struct Vt {
     drop_in_place: unsafe fn(*mut Data),
-    size: usize,
-    align: usize,
-    trace: fn(*const Data),
+    trace: fn(*const Data, &mut Tracer),
+    type_name: fn() -> &'static str,
+    type_id: fn() -> TypeId,
 }
 
 impl Vt {
@@ -164,17 +587,17 @@ impl Vt {
 
             impl<T: Trace> HasVt<T> for T {
                 const VT: &'static Vt = unsafe {
-                    use std::mem::align_of;
-                    use std::mem::size_of;
                     use std::ptr::drop_in_place;
 
                     &Vt {
                         drop_in_place: transmute::<unsafe fn(*mut T), unsafe fn(*mut Data)>(
                             drop_in_place::<T> as unsafe fn(*mut T),
                         ),
-                        size: size_of::<T>(),
-                        align: align_of::<T>(),
-                        trace: transmute::<unsafe fn(&T), fn(*const Data)>(<T as Trace>::trace),
+                        trace: transmute::<unsafe fn(&T, &mut Tracer), fn(*const Data, &mut Tracer)>(
+                            <T as Trace>::trace,
+                        ),
+                        type_name: std::any::type_name::<T>,
+                        type_id: TypeId::of::<T>,
                     }
                 };
             }
@@ -197,10 +620,10 @@ mod tests {
         }
 
         unsafe impl Trace for Test {
-            unsafe fn trace(&self) {}
+            unsafe fn trace(&self, _: &mut Tracer) {}
         }
 
-        let cx = Allocator::new(Config::default());
+        let cx = Allocator::new(Config::default(), 0);
         let v = Test {
             value: "test".to_owned(),
         };
@@ -208,8 +631,70 @@ mod tests {
         unsafe {
             println!("{}", (*v).data.value);
         }
+    }
+
+    #[test]
+    fn reuse_cells() {
+        struct Test {
+            value: u64,
+        }
+
+        unsafe impl Trace for Test {
+            unsafe fn trace(&self, _: &mut Tracer) {}
+        }
+
+        let mut cx = Allocator::new(Config::default(), 0);
+        let a = GcCell::erase(cx.alloc(Test { value: 0 }));
+        let b = GcCell::erase(cx.alloc(Test { value: 1 }));
+        assert_eq!(cx.bytes.get(), 64);
+
+        let heap = cx.heap.get_mut();
+        let freed: usize = heap
+            .pages_mut()
+            .map(|page| unsafe { page.retain(|cell| cell != a) })
+            .sum();
+        assert_eq!(freed, 1);
+        assert_eq!(heap.objects().collect::<Vec<_>>(), [b]);
+
+        // the freed cell is reused before the rest of the page
+        let c = cx.alloc(Test { value: 2 });
+        assert_eq!(GcCell::erase(c), a);
+        assert_eq!(unsafe { (*c).data.value }, 2);
+
+        let heap = cx.heap.get_mut();
+        for page in heap.pages_mut() {
+            unsafe { page.retain(|_| false) };
+        }
+        heap.free_empty_pages();
+        assert_eq!(heap.pages().count(), 0);
+    }
+
+    #[test]
+    fn mark_stack() {
+        // never dereferenced
+        let ptr = |i: usize| i as *mut GcCell<Data>;
+
+        let mut stack = MarkStack::new(2);
+        for i in 1..=5 {
+            stack.push(ptr(i));
+        }
+        assert_eq!(stack.chunks.len(), 3);
+
+        for i in (3..=5).rev() {
+            assert_eq!(stack.pop(), Some(ptr(i)));
+        }
+        assert_eq!(stack.chunks.len(), 1);
+        assert!(stack.spare.is_some());
+
+        stack.push(ptr(6));
+        assert!(stack.spare.is_none());
+        assert_eq!(stack.chunks.len(), 2);
 
-        unsafe { GcCell::free(GcCell::erase(v)) }
+        for i in [6, 2, 1] {
+            assert_eq!(stack.pop(), Some(ptr(i)));
+        }
+        assert_eq!(stack.pop(), None);
+        assert!(stack.is_empty());
     }
 
     #[test]
@@ -218,17 +703,15 @@ mod tests {
 
         struct Test {}
         unsafe impl Trace for Test {
-            unsafe fn trace(&self) {
+            unsafe fn trace(&self, _: &mut Tracer) {
                 TRACED.store(true, Ordering::SeqCst);
             }
         }
 
-        let cx = Allocator::new(Config::default());
+        let cx = Allocator::new(Config::default(), 0);
         let v = cx.alloc(Test {});
-        unsafe { GcCell::trace(GcCell::erase(v)) }
+        unsafe { GcCell::trace(GcCell::erase(v), &mut Tracer::mark(&cx)) }
 
         assert!(TRACED.load(Ordering::SeqCst));
-
-        unsafe { GcCell::free(GcCell::erase(v)) }
     }
 }
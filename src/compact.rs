@@ -0,0 +1,105 @@
+//! Mark-compact.
+//!
+//! Compaction moves the live objects of each size class into as few pages
+//! as possible, by evacuating the sparsest pages into the free cells of the
+//! densest ones, so it needs no memory beyond the pages which already exist.
+//! It then rewrites every reference to the moved objects: handle slots, the
+//! slots of `Global` and `Weak` handles, and the reference fields of objects,
+//! which are found by tracing every object with an updating `Tracer`.
+//!
+//! Objects are moved by copying their bytes, so the old pages are
+//! freed without dropping their contents. Objects in the large object
+//! space are never moved.
+//!
+//! Dereferencing a `Local` produces a plain reference into the heap, which
+//! can't be updated, so objects may only move while no `Scope` is alive.
+
+use crate::alloc::Allocator;
+use crate::alloc::Data;
+use crate::alloc::GcCell;
+use crate::gc::Tracer;
+use crate::handle::ScopeData;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+type OpaquePtr = *mut GcCell<Data>;
+
+/// Maps the old location of each moved object to its new one.
+pub(crate) struct Forwarding {
+    map: HashMap<OpaquePtr, OpaquePtr>,
+}
+
+impl Forwarding {
+    /// The new location of `ptr`, or `ptr` itself if it was not moved.
+    #[inline]
+    pub(crate) fn get(&self, ptr: OpaquePtr) -> OpaquePtr {
+        self.map.get(&ptr).copied().unwrap_or(ptr)
+    }
+
+    #[inline]
+    pub(crate) fn update(&self, slot: &Cell<OpaquePtr>) {
+        slot.set(self.get(slot.get()));
+    }
+}
+
+/// Move all objects into as few pages as possible, and promote them to the old generation.
+///
+/// ## Safety
+/// Must be called after a full cycle, while no `Scope` is alive.
+pub(crate) unsafe fn compact(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    let allocator = &*allocator;
+    debug_assert!(!allocator.marking.get(), "compaction during marking");
+
+    let heap = &mut *allocator.heap.get();
+    let mut forwarding = Forwarding {
+        map: HashMap::new(),
+    };
+    let old_pages = heap.compact(|object, new| {
+        forwarding.map.insert(object, new);
+    });
+
+    debug!(moved = forwarding.map.len(); "compact");
+
+    for page in heap.pages_mut() {
+        page.has_young = false;
+    }
+    // large objects are never moved, but they are promoted as well
+    for object in heap.objects().chain((*allocator.large.get()).objects()) {
+        GcCell::set_old(object, true);
+        GcCell::set_remembered(object, false);
+    }
+    allocator.young_bytes.set(0);
+    // there are no young objects left
+    (*allocator.remembered.get()).clear();
+
+    // the old pages are still allocated at this point,
+    // so their objects can't be confused with the new ones
+    update(scope_data, allocator, &forwarding);
+
+    // the contents were moved, so dropping the old pages does not drop them
+    drop(old_pages);
+}
+
+/// Rewrite all references to moved objects.
+unsafe fn update(scope_data: *mut ScopeData, allocator: &Allocator, forwarding: &Forwarding) {
+    let scope_data = &mut *scope_data;
+    scope_data.for_each_slot_mut(|slot| *slot = forwarding.get(*slot));
+    for slot in scope_data.globals() {
+        forwarding.update(&slot);
+    }
+    for slot in scope_data.weaks() {
+        forwarding.update(&slot);
+    }
+
+    let finalizers = &mut *allocator.finalizers.get();
+    let queue = &mut *allocator.finalization_queue.get();
+    for finalizer in finalizers.iter_mut().chain(queue.iter_mut()) {
+        finalizer.cell = forwarding.get(finalizer.cell);
+    }
+
+    let mut tracer = Tracer::update(forwarding);
+    let heap = &*allocator.heap.get();
+    for object in heap.objects().chain((*allocator.large.get()).objects()) {
+        GcCell::trace(object, &mut tracer);
+    }
+}
@@ -0,0 +1,176 @@
+//! Ephemeron tables.
+//!
+//! An ephemeron is a key-value pair, where the value is only reachable
+//! if the key is reachable from somewhere else. The table itself does
+//! not keep its keys alive, and entries with dead keys are removed by
+//! the collector.
+//!
+//! This can't be expressed with a regular `Trace` implementation, because
+//! whether a key is reachable is only known once marking is done.
+//! Instead, tracing a table only registers it with the collector, which
+//! then traces the values of all entries with marked keys, and repeats
+//! until no new objects are marked.
+
+use crate::alloc::Allocator;
+use crate::alloc::Data;
+use crate::alloc::GcCell;
+use crate::compact::Forwarding;
+use crate::gc::Trace;
+use crate::gc::Tracer;
+use crate::handle::Local;
+use crate::handle::ParentScope;
+use crate::handle::Scope;
+use std::cell::Cell;
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ptr::null_mut;
+
+type OpaquePtr = *mut GcCell<Data>;
+
+/// A map with weakly held keys, and values which are
+/// only reachable as long as their key is reachable.
+///
+/// Keys are compared by identity.
+///
+/// ## Example
+/// ```rust,ignore
+/// let table = EphemeronTable::<Foo, Member<Bar>>::new(s);
+/// table.insert(s, &foo, bar.to_member());
+/// ```
+pub struct EphemeronTable<K: Trace, V: Trace> {
+    /// The object which holds this table.
+    this: Cell<OpaquePtr>,
+    entries: RefCell<HashMap<OpaquePtr, V>>,
+    _key: std::marker::PhantomData<K>,
+}
+
+impl<K: Trace, V: Trace> EphemeronTable<K, V> {
+    /// Tables may only exist directly on the heap.
+    pub fn new<'a>(scope: &mut Scope<'a>) -> Local<'a, Self> {
+        let table = Local::new(
+            scope,
+            EphemeronTable {
+                this: Cell::new(null_mut()),
+                entries: RefCell::new(HashMap::new()),
+                _key: std::marker::PhantomData,
+            },
+        );
+        unsafe { table.this.set(GcCell::erase(*table.as_ptr())) };
+        table
+    }
+
+    pub fn insert(&self, scope: &Scope<'_>, key: &Local<'_, K>, value: V) -> Option<V> {
+        unsafe {
+            let allocator = &*scope.allocator();
+            // the table is rooted, so `value` must not refer to freed objects
+            value.trace(&mut Tracer::check(allocator));
+            // the table may be in the old generation, in which case it
+            // has to be traced again by the next minor collection,
+            // because `value` may refer to young objects.
+            allocator.remember(self.this.get());
+        }
+
+        let key = GcCell::erase(key.to_member().as_raw());
+        self.entries.borrow_mut().insert(key, value)
+    }
+
+    pub fn get(&self, key: &Local<'_, K>) -> Option<Ref<'_, V>> {
+        let key = GcCell::erase(key.to_member().as_raw());
+        Ref::filter_map(self.entries.borrow(), |entries| entries.get(&key)).ok()
+    }
+
+    pub fn contains_key(&self, key: &Local<'_, K>) -> bool {
+        let key = GcCell::erase(key.to_member().as_raw());
+        self.entries.borrow().contains_key(&key)
+    }
+
+    pub fn remove(&self, key: &Local<'_, K>) -> Option<V> {
+        let key = GcCell::erase(key.to_member().as_raw());
+        self.entries.borrow_mut().remove(&key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().is_empty()
+    }
+}
+
+/// Type-erased interface used by the collector.
+pub(crate) trait Ephemeron {
+    /// Trace the values of all entries with live keys.
+    ///
+    /// ## Safety
+    /// Must only be called during the mark phase.
+    unsafe fn trace_values(&self, allocator: &Allocator, tracer: &mut Tracer);
+
+    /// Remove all entries with dead keys.
+    ///
+    /// ## Safety
+    /// Must only be called once marking is done.
+    unsafe fn remove_dead(&self, allocator: &Allocator);
+
+    /// Rewrite the keys of all entries, and the references in their
+    /// values, after compaction moved the objects they refer to.
+    ///
+    /// ## Safety
+    /// Must only be called while updating references after compaction.
+    unsafe fn update(&self, forwarding: &Forwarding, tracer: &mut Tracer);
+
+    /// Visit all keys as weak references, and trace all values.
+    ///
+    /// Used to check that they are all live, and to look for a field.
+    ///
+    /// ## Safety
+    /// Must only be called while verifying the heap, or with a tracer which
+    /// only compares the visited references.
+    unsafe fn visit_entries(&self, tracer: &mut Tracer);
+}
+
+impl<K: Trace, V: Trace> Ephemeron for EphemeronTable<K, V> {
+    unsafe fn trace_values(&self, allocator: &Allocator, tracer: &mut Tracer) {
+        for (&key, value) in self.entries.borrow().iter() {
+            if !allocator.is_dead(key) {
+                value.trace(tracer);
+            }
+        }
+    }
+
+    unsafe fn remove_dead(&self, allocator: &Allocator) {
+        self.entries
+            .borrow_mut()
+            .retain(|&key, _| !allocator.is_dead(key));
+    }
+
+    unsafe fn update(&self, forwarding: &Forwarding, tracer: &mut Tracer) {
+        self.this.set(forwarding.get(self.this.get()));
+
+        let mut entries = self.entries.borrow_mut();
+        *entries = std::mem::take(&mut *entries)
+            .into_iter()
+            .map(|(key, value)| {
+                value.trace(tracer);
+                (forwarding.get(key), value)
+            })
+            .collect();
+    }
+
+    unsafe fn visit_entries(&self, tracer: &mut Tracer) {
+        for (&key, value) in self.entries.borrow().iter() {
+            // keys are held weakly, but entries with dead keys
+            // must have been removed by the last collection
+            tracer.visit_weak(&Cell::new(key.cast::<GcCell<K>>()));
+            value.trace(tracer);
+        }
+    }
+}
+
+unsafe impl<K: Trace, V: Trace> Trace for EphemeronTable<K, V> {
+    #[inline]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        tracer.visit_ephemeron(self);
+    }
+}
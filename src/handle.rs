@@ -30,20 +30,25 @@
 
 use crate::alloc::Allocator;
 use crate::alloc::Data;
+use crate::alloc::Finalizer;
 use crate::alloc::GcCell;
 use crate::gc::gc;
+use crate::gc::gc_step;
+use crate::gc::gc_young;
+use crate::gc::live_heap;
+use crate::gc::run_finalizers;
+use crate::gc::AllocError;
+use crate::gc::HeapId;
 use crate::gc::Trace;
+use crate::gc::Tracer;
+use std::cell::Cell;
 use std::cmp;
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::ops::DerefMut;
 use std::ptr::null_mut;
-
-// TODO: `project!` macro for safe access of `Member` fields through `Local`
-// TODO: Cell-like types with write barries for interior mutability of reference fields
-//       e.g. MemberMut<T> with `set` method that calls the write barrier
-//       see `gc-arena` for how they do field projections and write barriers
-// TODO: add `Global<T>` type, which is a reference-counted handle
+use std::rc::Rc;
+use std::rc::Weak as RcWeak;
 
 // Surely pages are at least 4kB!
 pub(crate) const BLOCK_SIZE: usize = 4096 / std::mem::size_of::<OpaquePtr>();
@@ -54,6 +59,9 @@ type OpaquePtr = Ptr<Data>;
 type Block = [OpaquePtr; BLOCK_SIZE];
 type BlockList = Vec<Box<Block>>;
 
+type SharedSlot = Cell<OpaquePtr>;
+type SharedSlotList = Vec<RcWeak<SharedSlot>>;
+
 type Invariant<'a, T = ()> = PhantomData<fn(&'a T) -> &'a T>;
 type Covariant<'a, T = ()> = PhantomData<&'a T>;
 
@@ -97,6 +105,18 @@ pub struct ScopeData {
     ///
     /// Blocks _must not move_, so they are boxed independently.
     blocks: BlockList,
+
+    /// Slots of all `Global` handles.
+    ///
+    /// Slots are owned by the handles, and entries of dropped
+    /// handles are removed during the next GC cycle.
+    globals: SharedSlotList,
+
+    /// Slots of all `Weak` handles.
+    ///
+    /// Unlike `globals`, these are not roots. They are cleared
+    /// when the object they refer to is freed.
+    weaks: SharedSlotList,
 }
 
 impl ScopeData {
@@ -107,6 +127,8 @@ impl ScopeData {
             limit: null_mut(),
             next_scope_level: 0,
             blocks: BlockList::new(),
+            globals: SharedSlotList::new(),
+            weaks: SharedSlotList::new(),
         };
 
         // Invariant: We must always have at least one block
@@ -140,10 +162,13 @@ impl ScopeData {
         };
         self.limit = unsafe { self.next.ptr.add(BLOCK_SIZE) };
 
-        debug!(
-            "index={}, ptr={:p}, limit={:p}",
-            self.next.index, self.next.ptr, self.limit,
-        );
+        debug!(index = self.next.index, ptr = self.next.ptr, limit = self.limit; "new handle block");
+    }
+
+    /// Number of allocated handle blocks.
+    #[inline]
+    pub(crate) fn block_count(&self) -> usize {
+        self.blocks.len()
     }
 
     #[cold]
@@ -153,28 +178,75 @@ impl ScopeData {
         drop(self.blocks.drain(last_used_block + 1..));
     }
 
-    pub(crate) fn iter(&self) -> ScopeDataIter {
-        debug!("iter");
-        let end = if self.tombstone.index == self.next.index {
-            debug!("tombstone.index == next.index");
+    /// Iterate over the slots of all live `Global` handles,
+    /// removing the ones which have been dropped.
+    pub(crate) fn globals(&mut self) -> impl Iterator<Item = Rc<SharedSlot>> + '_ {
+        self.globals.retain(|slot| slot.strong_count() > 0);
+        self.globals.iter().filter_map(|slot| slot.upgrade())
+    }
+
+    /// Iterate over the slots of all live `Weak` handles,
+    /// removing the ones which have been dropped.
+    pub(crate) fn weaks(&mut self) -> impl Iterator<Item = Rc<SharedSlot>> + '_ {
+        self.weaks.retain(|slot| slot.strong_count() > 0);
+        self.weaks.iter().filter_map(|slot| slot.upgrade())
+    }
+
+    /// End of the range of slots which are treated as roots.
+    fn end(&self) -> *mut OpaquePtr {
+        if self.tombstone.index == self.next.index {
+            trace!("tombstone.index == next.index");
             cmp::max(self.tombstone.ptr, self.next.ptr)
         } else if self.tombstone.index < self.next.index {
-            debug!("tombstone.index < next.index");
+            trace!("tombstone.index < next.index");
             self.next.ptr
         } else {
-            debug!("tombstone.index > next.index");
+            trace!("tombstone.index > next.index");
             self.tombstone.ptr
-        };
+        }
+    }
 
+    pub(crate) fn iter(&self) -> ScopeDataIter<'_> {
+        trace!("iter");
         ScopeDataIter {
             scope_data: self,
             next_block_index: 1,
             next: self.blocks[0].as_ptr(),
             block_limit: unsafe { self.blocks[0].as_ptr().add(BLOCK_SIZE) },
-            end,
+            end: self.end(),
             lifetime: PhantomData,
         }
     }
+
+    /// Visit the same slots as [`ScopeData::iter`], allowing them to be changed.
+    pub(crate) fn for_each_slot_mut(&mut self, mut f: impl FnMut(&mut OpaquePtr)) {
+        let end = self.end();
+        for block in self.blocks.iter_mut() {
+            let range = block.as_mut_ptr_range();
+            let is_last = range.contains(&end) || range.end == end;
+            for slot in block.iter_mut() {
+                if std::ptr::eq(slot, end) {
+                    break;
+                }
+                f(slot);
+            }
+            if is_last {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for ScopeData {
+    fn drop(&mut self) {
+        // `Global` and `Weak` handles may outlive the heap, so they are
+        // cleared to make any further use of them panic instead of dangle.
+        for slot in self.globals.drain(..).chain(self.weaks.drain(..)) {
+            if let Some(slot) = slot.upgrade() {
+                slot.set(null_mut());
+            }
+        }
+    }
 }
 
 pub(crate) struct ScopeDataIter<'a> {
@@ -199,11 +271,11 @@ impl Iterator for ScopeDataIter<'_> {
 
     fn next(&mut self) -> Option<Self::Item> {
         if self.next == self.end {
-            debug!("next=none");
+            trace!("next=none");
             // at end - no more handles
             None
         } else if self.next == self.block_limit {
-            debug!("next block, next=block[0]");
+            trace!("next block, next=block[0]");
 
             // at block end - go to next block
             // we are guaranteed to have another block,
@@ -214,7 +286,7 @@ impl Iterator for ScopeDataIter<'_> {
 
             Some(unsafe { *self.next })
         } else {
-            debug!(
+            trace!(
                 "next=block[{}]",
                 (self.next as usize - unsafe { self.block_limit.sub(BLOCK_SIZE) } as usize) / 8
             );
@@ -248,12 +320,7 @@ impl<'scope> Scope<'scope> {
         let level = (*scope_data).next_scope_level;
         (*scope_data).next_scope_level += 1;
 
-        debug!(
-            "prev_next={:p}, prev_limit={:p}, level={}",
-            prev_next.ptr,
-            (*scope_data).limit,
-            level
-        );
+        trace!(prev_next = prev_next.ptr, prev_limit = (*scope_data).limit, level = level; "new scope");
 
         Scope {
             scope_data,
@@ -264,17 +331,114 @@ impl<'scope> Scope<'scope> {
         }
     }
 
-    /// Trigger a GC cycle.
+    /// Perform one incremental step of a GC cycle.
+    ///
+    /// Each step traces at most `Config::mark_slice` objects. The grey set
+    /// is kept between steps, and the step which empties it also completes
+    /// the cycle by re-scanning the roots and sweeping.
+    ///
+    /// While a cycle is in progress, references may only be stored into
+    /// existing objects through [`MemberCell`], [`OptionMemberCell`] and
+    /// [`WeakMember`], which report the store to the collector.
     #[inline]
     pub fn collect(&mut self) {
-        // TODO: incremental collection
-        self.collect_all()
+        unsafe {
+            gc_step(self.scope_data, self.allocator);
+            run_finalizers(self.scope_data, self.allocator);
+        }
     }
 
     /// Trigger a _full_ GC cycle.
+    ///
+    /// If an incremental cycle is in progress, it is finished.
     #[inline]
     pub fn collect_all(&mut self) {
-        gc(self.scope_data, self.allocator)
+        unsafe {
+            gc(self.scope_data, self.allocator);
+            run_finalizers(self.scope_data, self.allocator);
+        }
+    }
+
+    /// Collect only the young generation.
+    ///
+    /// Objects which survive are promoted to the old generation, and are
+    /// only freed by a full cycle. Only the handles and the old objects in
+    /// the remembered set are traced, so references may only be stored into
+    /// existing objects through [`MemberCell`], [`OptionMemberCell`] and
+    /// [`WeakMember`], which add their object to the remembered set.
+    ///
+    /// If an incremental cycle is in progress, this performs a step instead.
+    #[inline]
+    pub fn collect_young(&mut self) {
+        unsafe {
+            gc_young(self.scope_data, self.allocator);
+            run_finalizers(self.scope_data, self.allocator);
+        }
+    }
+
+    /// Notify the collector that `value` was stored into `parent`.
+    ///
+    /// Between incremental steps, the collector may have already traced
+    /// `parent`. Without the barrier, the newly stored reference would not
+    /// be visited, and `value` could be freed while it is still reachable.
+    ///
+    /// The same is true for minor collections, which do not trace the
+    /// old generation at all. An old `parent` which now refers to a young
+    /// `value` is added to the remembered set.
+    #[inline]
+    pub(crate) fn write_barrier<P: Trace, T: Trace>(
+        &self,
+        parent: &Local<'_, P>,
+        value: &Local<'_, T>,
+    ) {
+        unsafe {
+            (*self.allocator).write_barrier(GcCell::erase(*parent.slot), GcCell::erase(*value.slot))
+        }
+    }
+
+    /// Register a finalizer for `object`.
+    ///
+    /// Once `object` is found to be unreachable, it is kept alive along with
+    /// everything it refers to, and `f` is queued. Queued finalizers run after
+    /// the collection which queued them completes, in the order they were
+    /// registered, and receive a `Local` to their object. Collections triggered
+    /// by allocation run them before the allocation.
+    ///
+    /// If the finalizer stores `object` somewhere reachable, it is resurrected.
+    /// Otherwise, it is freed by a later cycle. Either way, the finalizer does
+    /// not run again unless it is registered again.
+    ///
+    /// Finalizers which are still registered or queued when the `Gc` is
+    /// dropped do not run.
+    ///
+    /// This is a method of `Scope` rather than `Gc`, because `object` is
+    /// a `Local`, which only exists while a `Scope` borrows the `Gc`.
+    ///
+    /// ## Example
+    /// ```rust,ignore
+    /// s.register_finalizer(&file, |s, file| {
+    ///     println!("closing {}", file.name);
+    /// });
+    /// ```
+    pub fn register_finalizer<T, F>(&self, object: &Local<'_, T>, f: F)
+    where
+        T: Trace,
+        F: for<'a> FnOnce(&mut Scope<'a>, Local<'a, T>) + 'static,
+    {
+        let finalizer = Finalizer {
+            cell: GcCell::erase(unsafe { *object.slot }),
+            run: Box::new(move |scope, cell| {
+                let object = unsafe { Local::alloc(scope.scope_data, cell.cast()) };
+                f(scope, object)
+            }),
+        };
+        unsafe { (*(*self.allocator).finalizers.get()).push(finalizer) };
+    }
+
+    /// Id of the `Gc` which this scope belongs to.
+    #[inline]
+    pub(crate) fn heap(&self) -> HeapId {
+        unsafe { (*self.allocator).id }
     }
 
     #[inline]
@@ -295,11 +459,11 @@ impl<'ctx> Drop for Scope<'ctx> {
             (*scope_data).next = self.prev_next;
             (*scope_data).next_scope_level -= 1;
 
-            debug!(
-                "\n  data.tombstone={tombstone:p}\n  data.next={next:p}\n  data.next_scope_level={level}",
+            trace!(
                 tombstone = (*scope_data).tombstone.ptr,
                 next = (*scope_data).next.ptr,
-                level = (*scope_data).next_scope_level
+                level = (*scope_data).next_scope_level;
+                "drop scope"
             );
 
             // handle scopes must be created and dropped in stack order
@@ -362,18 +526,50 @@ impl<'scope, 'outer: 'scope> DerefMut for EscapeScope<'scope, 'outer> {
 
 #[inline]
 fn alloc<'scope, T: Trace + 'scope>(scope: &mut impl ParentScope<'scope>, value: T) -> Ptr<T> {
+    match try_alloc(scope, value) {
+        Ok(ptr) => ptr,
+        Err(e) => panic!("{e}"),
+    }
+}
+
+#[inline]
+fn try_alloc<'scope, T: Trace + 'scope>(
+    scope: &mut impl ParentScope<'scope>,
+    value: T,
+) -> Result<Ptr<T>, AllocError> {
     unsafe {
         let scope_data = scope.scope_data();
         let allocator = scope.allocator();
 
-        // TODO: trigger GC here if heap is somewhat full
         if (*allocator).config.stress {
             // stress-test the GC by running it before every allocation
             super::gc::gc(scope_data, allocator);
+        } else if (*allocator).should_collect() {
+            gc_step(scope_data, allocator);
+        } else if (*allocator).should_collect_young() {
+            gc_young(scope_data, allocator);
         }
 
         assert!(scope.is_active(), "alloc outside of current handle scope");
-        (*allocator).alloc(value)
+        run_finalizers(scope_data, allocator);
+
+        if (*allocator).check_limit::<T>().is_err() {
+            debug!(bytes = (*allocator).bytes.get(), limit = (*allocator).config.heap_limit; "heap limit reached, collecting");
+            // objects which died after an in-progress cycle started
+            // are only found by the next one
+            let was_marking = (*allocator).marking.get();
+            super::gc::gc(scope_data, allocator);
+            if was_marking {
+                super::gc::gc(scope_data, allocator);
+            }
+            run_finalizers(scope_data, allocator);
+            (*allocator).check_limit::<T>()?;
+        }
+
+        // once the object is rooted, everything it refers to is kept
+        // alive, but only if it has not been freed already
+        value.trace(&mut Tracer::check(&*allocator));
+        Ok((*allocator).alloc(value))
     }
 }
 
@@ -413,47 +609,244 @@ fn alloc<'scope, T: Trace + 'scope>(scope: &mut impl ParentScope<'scope>, value:
 /// // but it is safe to dereference as it is still
 /// // reachable through `foo`:
 /// let bar: &Bar = unsafe { foo.bar.get() };
+///
+/// // or by putting it into a `Local`:
+/// let bar: Local<Bar> = project!(s, foo.bar);
 /// ```
+///
+/// A `Member` can't be changed once its object is allocated. Use [`MemberCell`]
+/// or [`OptionMemberCell`] for fields which are changed later, because every
+/// store into an existing object has to be reported to the collector.
+///
+/// Using a `Member` in another `Gc` than the one it was created in
+/// panics, see [`Gc`](crate::Gc).
+///
+/// `Member` is not `Copy`, because compaction moves objects and rewrites
+/// the pointers to them in place, which needs a `Cell`. Use `clone` to
+/// duplicate one.
 pub struct Member<T: Trace> {
-    pub(crate) ptr: Ptr<T>,
+    /// Only ever changed by the collector, when the object is moved.
+    pub(crate) ptr: Cell<Ptr<T>>,
+
+    /// Id of the `Gc` which the object belongs to.
+    pub(crate) heap: HeapTag,
 }
 
 impl<T: Trace> Member<T> {
+    #[inline]
+    pub(crate) fn from_raw(ptr: Ptr<T>, heap: HeapTag) -> Self {
+        Member {
+            ptr: Cell::new(ptr),
+            heap,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn as_raw(&self) -> Ptr<T> {
+        self.ptr.get()
+    }
+
+    /// Panics if the object does not belong to the live `Gc`.
+    #[inline]
+    pub(crate) fn check_heap(&self) {
+        self.heap.check(live_heap());
+    }
+
     /// Dereference the inner pointer and obtain a reference to the object.
     ///
     /// ## Safety
     /// The object must not have been freed yet, and still be reachable.
+    /// It must also not have been moved by
+    /// [`Gc::compact`](crate::Gc::compact) since the `Member` was read,
+    /// which only updates the references stored in the heap.
     #[inline]
     pub unsafe fn get(&self) -> &T {
-        &*GcCell::data(self.ptr)
+        self.check_heap();
+        &*GcCell::data(self.as_raw())
     }
 
     /// Dereference the inner pointer and obtain a reference to the object.
     ///
     /// ## Safety
     /// The object must not have been freed yet, and still be reachable.
+    /// It must also not have been moved by
+    /// [`Gc::compact`](crate::Gc::compact) since the `Member` was read,
+    /// which only updates the references stored in the heap.
     #[inline]
     pub unsafe fn in_scope<'a>(self, scope: &mut Scope<'a>) -> Local<'a, T> {
-        Local::alloc(scope.scope_data, self.ptr)
+        self.heap.check(scope.heap());
+        Local::alloc(scope.scope_data, self.as_raw())
     }
 
     /// Dereference the inner pointer and obtain a reference to the object.
     ///
     /// ## Safety
     /// The object must not have been freed yet, and still be reachable.
+    /// It must also not have been moved by
+    /// [`Gc::compact`](crate::Gc::compact) since the `Member` was read,
+    /// which only updates the references stored in the heap.
     #[inline]
     pub unsafe fn move_to(self, local: &mut Local<'_, T>) {
-        local.set_raw(self.ptr)
+        self.check_heap();
+        local.set_raw(self.as_raw())
     }
 }
 
 impl<T: Trace> Clone for Member<T> {
     fn clone(&self) -> Self {
-        *self
+        Member::from_raw(self.as_raw(), self.heap)
     }
 }
 
-impl<T: Trace> Copy for Member<T> {}
+/// Id of the `Gc` which a reference field belongs to.
+///
+/// Only recorded with debug assertions, so that reference fields stay the
+/// size of a pointer. Without them, a reference to an object of another
+/// `Gc` is still rejected once it is stored into a new object, because its
+/// object is not live in this heap.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct HeapTag {
+    #[cfg(debug_assertions)]
+    id: HeapId,
+}
+
+impl HeapTag {
+    #[inline]
+    pub(crate) fn new(id: HeapId) -> Self {
+        #[cfg(not(debug_assertions))]
+        let _ = id;
+        Self {
+            #[cfg(debug_assertions)]
+            id,
+        }
+    }
+
+    /// Panics if the reference is used in the `Gc` with id `expected`,
+    /// but belongs to another one.
+    #[inline]
+    pub(crate) fn check(self, expected: HeapId) {
+        #[cfg(debug_assertions)]
+        assert!(
+            self.id == expected,
+            "found a reference to an object of another `Gc`"
+        );
+        #[cfg(not(debug_assertions))]
+        let _ = expected;
+    }
+}
+
+/// A mutable `Member` field.
+///
+/// Every `set` goes through the collector's write barrier, which keeps
+/// incremental and minor collections sound when a reference is stored
+/// into an object which has already been traced. The barrier has to know
+/// which object is written to, so `set` takes the object as well.
+///
+/// The field may also be stored outside of the object's own memory, such
+/// as in a `Vec`, as long as the object's `Trace` implementation visits it.
+///
+/// ## Example
+/// ```rust,ignore
+/// #[derive(Trace)]
+/// struct Foo {
+///   bar: MemberCell<Bar>,
+/// }
+///
+/// let foo = Local::new(s, Foo { bar: MemberCell::new(bar.to_member()) });
+/// foo.bar.set(s, &foo, &other_bar);
+/// ```
+///
+/// Other kinds of interior mutability would bypass the barrier, so
+/// a `RefCell` may not hold references:
+/// ```compile_fail,E0277
+/// # use gc::*;
+/// # use std::cell::RefCell;
+/// #[derive(Trace)]
+/// struct Foo {
+///   bar: RefCell<Option<Member<u32>>>,
+/// }
+/// ```
+pub struct MemberCell<T: Trace> {
+    pub(crate) ptr: Cell<Ptr<T>>,
+    pub(crate) heap: Cell<HeapTag>,
+}
+
+impl<T: Trace> MemberCell<T> {
+    #[inline]
+    pub fn new(value: Member<T>) -> Self {
+        Self {
+            ptr: Cell::new(value.as_raw()),
+            heap: Cell::new(value.heap),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> Member<T> {
+        Member::from_raw(self.ptr.get(), self.heap.get())
+    }
+
+    /// Store `value` into this field of `parent`.
+    ///
+    /// Panics if the field is not a part of `parent`.
+    #[inline]
+    pub fn set<P: Trace>(&self, scope: &Scope<'_>, parent: &Local<'_, P>, value: &Local<'_, T>) {
+        assert_field_of::<P, T>(parent, &self.ptr);
+        scope.write_barrier(parent, value);
+        self.ptr.set(unsafe { *value.slot });
+        self.heap.set(HeapTag::new(scope.heap()));
+    }
+}
+
+/// An optional mutable `Member` field.
+///
+/// See [`MemberCell`].
+pub struct OptionMemberCell<T: Trace> {
+    pub(crate) ptr: Cell<Ptr<T>>,
+    pub(crate) heap: Cell<HeapTag>,
+}
+
+impl<T: Trace> OptionMemberCell<T> {
+    #[inline]
+    pub fn new(value: Option<Member<T>>) -> Self {
+        Self {
+            ptr: Cell::new(value.as_ref().map_or(null_mut(), |v| v.as_raw())),
+            heap: Cell::new(value.map_or(HeapTag::default(), |v| v.heap)),
+        }
+    }
+
+    #[inline]
+    pub fn get(&self) -> Option<Member<T>> {
+        let ptr = self.ptr.get();
+        (!ptr.is_null()).then(|| Member::from_raw(ptr, self.heap.get()))
+    }
+
+    /// Store `value` into this field of `parent`.
+    ///
+    /// Panics if the field is not a part of `parent`.
+    #[inline]
+    pub fn set<P: Trace>(
+        &self,
+        scope: &Scope<'_>,
+        parent: &Local<'_, P>,
+        value: Option<&Local<'_, T>>,
+    ) {
+        assert_field_of::<P, T>(parent, &self.ptr);
+        match value {
+            Some(value) => {
+                scope.write_barrier(parent, value);
+                self.ptr.set(unsafe { *value.slot });
+                self.heap.set(HeapTag::new(scope.heap()));
+            }
+            None => self.ptr.set(null_mut()),
+        }
+    }
+}
+
+impl<T: Trace> Default for OptionMemberCell<T> {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
 
 pub struct Local<'scope, T: Trace> {
     /// Pointer to the handle slot which contains the actual memory location of `T`.
@@ -463,6 +856,10 @@ pub struct Local<'scope, T: Trace> {
 }
 
 impl<'scope, T: Trace> Local<'scope, T> {
+    /// Allocate `value` on the heap.
+    ///
+    /// Panics if `value` refers to an object which was already freed,
+    /// such as through a [`Member`] kept from before a collection.
     pub fn new(scope: &mut Scope<'scope>, value: T) -> Self
     where
         T: Trace + 'scope,
@@ -475,11 +872,25 @@ impl<'scope, T: Trace> Local<'scope, T> {
         }
     }
 
+    /// Like [`Local::new`], but returns an error instead of panicking
+    /// if the heap limit would be exceeded.
+    ///
+    /// A full collection is run before giving up.
+    pub fn try_new(scope: &mut Scope<'scope>, value: T) -> Result<Self, AllocError>
+    where
+        T: Trace + 'scope,
+    {
+        unsafe {
+            let ptr = try_alloc(scope, value)?;
+            Ok(Self::alloc(scope.scope_data, ptr))
+        }
+    }
+
     pub(crate) unsafe fn alloc(scope_data: *mut ScopeData, ptr: Ptr<T>) -> Self {
         let data = &mut *scope_data;
         let slot = data.alloc_handle() as *mut Ptr<T>;
         *slot = ptr;
-        debug!("{slot:p} = {ptr:p}, next = {next:p}", next = data.next.ptr);
+        trace!(slot = slot, ptr = ptr, next = data.next.ptr; "new handle");
 
         Local {
             slot,
@@ -488,7 +899,8 @@ impl<'scope, T: Trace> Local<'scope, T> {
     }
 
     pub fn to_member(&self) -> Member<T> {
-        unsafe { Member { ptr: *self.slot } }
+        // a `Local` can't outlive its `Gc`, which is the live one
+        unsafe { Member::from_raw(*self.slot, HeapTag::new(live_heap())) }
     }
 
     // TODO: check that you can't leak call this on a scope that has a child scope
@@ -525,6 +937,404 @@ impl<'scope, T: Trace> Deref for Local<'scope, T> {
     }
 }
 
+/// A handle whose slot may be overwritten.
+///
+/// Allocating a new handle for every step of a loop which walks a linked
+/// structure is wasteful, as none of them are freed until the scope ends.
+/// A `LocalMut` instead reuses the same slot for every object it refers to.
+///
+/// The `'scope` lifetime is invariant, so it may only store objects
+/// from handles in the same scope.
+///
+/// ## Example
+/// ```rust,ignore
+/// let mut node = LocalMut::new(s, &head);
+/// while unsafe { node.follow(|node| &node.next) } {
+///   println!("{}", node.value);
+/// }
+/// ```
+pub struct LocalMut<'scope, T: Trace> {
+    local: Local<'scope, T>,
+
+    lifetime: Invariant<'scope>,
+}
+
+impl<'scope, T: Trace> LocalMut<'scope, T> {
+    pub fn new(scope: &mut Scope<'scope>, value: &Local<'_, T>) -> Self {
+        LocalMut {
+            local: value.in_scope(scope),
+            lifetime: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn set(&mut self, value: &Local<'scope, T>) {
+        unsafe { self.local.set_raw(*value.slot) };
+    }
+
+    /// Store the object referenced by a field of `parent`.
+    ///
+    /// Returns `false` without changing the stored object
+    /// if the field is an empty optional reference.
+    ///
+    /// `parent` is rooted, so the object referenced by the field is live,
+    /// see [`project!`](crate::project!). Panics if the field is not a part
+    /// of `parent`.
+    #[inline]
+    pub fn set_member<P: Trace, F: MemberField<T>>(
+        &mut self,
+        parent: &Local<'_, P>,
+        field: impl FnOnce(&P) -> &F,
+    ) -> bool {
+        match read_field(parent, field) {
+            Some(member) => {
+                unsafe { self.local.set_raw(member.as_raw()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Replace the stored object with the one referenced by its own field.
+    ///
+    /// See [`LocalMut::set_member`].
+    #[inline]
+    pub fn follow<F: MemberField<T>>(&mut self, field: impl FnOnce(&T) -> &F) -> bool {
+        match read_field(&self.local, field) {
+            Some(member) => {
+                unsafe { self.local.set_raw(member.as_raw()) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[inline]
+    pub fn as_local(&self) -> &Local<'scope, T> {
+        &self.local
+    }
+}
+
+impl<'scope, T: Trace> Deref for LocalMut<'scope, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.local
+    }
+}
+
+/// A field which refers to another object.
+///
+/// Implemented for [`Member`], [`MemberCell`], and [`OptionMemberCell`].
+pub trait MemberField<T: Trace>: private::Sealed {
+    /// What [`project!`](crate::project!) evaluates to for this field:
+    /// `Local<'a, T>`, or `Option<Local<'a, T>>` for optional references.
+    type Projected<'a>;
+
+    #[doc(hidden)]
+    fn member(&self) -> Option<Member<T>>;
+
+    #[doc(hidden)]
+    fn slot(&self) -> &Cell<Ptr<T>>;
+
+    #[doc(hidden)]
+    fn projected(local: Option<Local<'_, T>>) -> Self::Projected<'_>;
+}
+
+impl<T: Trace> private::Sealed for Member<T> {}
+impl<T: Trace> MemberField<T> for Member<T> {
+    type Projected<'a> = Local<'a, T>;
+
+    #[inline]
+    fn member(&self) -> Option<Member<T>> {
+        Some(self.clone())
+    }
+
+    #[inline]
+    fn slot(&self) -> &Cell<Ptr<T>> {
+        &self.ptr
+    }
+
+    #[inline]
+    fn projected(local: Option<Local<'_, T>>) -> Self::Projected<'_> {
+        local.unwrap()
+    }
+}
+
+impl<T: Trace> private::Sealed for MemberCell<T> {}
+impl<T: Trace> MemberField<T> for MemberCell<T> {
+    type Projected<'a> = Local<'a, T>;
+
+    #[inline]
+    fn member(&self) -> Option<Member<T>> {
+        Some(self.get())
+    }
+
+    #[inline]
+    fn slot(&self) -> &Cell<Ptr<T>> {
+        &self.ptr
+    }
+
+    #[inline]
+    fn projected(local: Option<Local<'_, T>>) -> Self::Projected<'_> {
+        local.unwrap()
+    }
+}
+
+impl<T: Trace> private::Sealed for OptionMemberCell<T> {}
+impl<T: Trace> MemberField<T> for OptionMemberCell<T> {
+    type Projected<'a> = Option<Local<'a, T>>;
+
+    #[inline]
+    fn member(&self) -> Option<Member<T>> {
+        self.get()
+    }
+
+    #[inline]
+    fn slot(&self) -> &Cell<Ptr<T>> {
+        &self.ptr
+    }
+
+    #[inline]
+    fn projected(local: Option<Local<'_, T>>) -> Self::Projected<'_> {
+        local
+    }
+}
+
+/// Access a reference field of an object through its `Local`.
+///
+/// `project!(s, local.field)` evaluates to a `Local` in scope `s` holding
+/// the object referenced by `field`, or `Option<Local>` if the field is
+/// an [`OptionMemberCell`].
+///
+/// Panics if the field is not a part of the object.
+///
+/// `local` is rooted, so the object referenced by the field is live:
+/// a [`Member`] whose object was already freed can't be stored into
+/// a new object, see [`Local::new`].
+///
+/// ## Example
+/// ```rust,ignore
+/// #[derive(Trace)]
+/// struct Foo {
+///   bar: Member<Bar>,
+/// }
+///
+/// let foo: Local<Foo> = /* ... */;
+/// let bar: Local<Bar> = project!(s, foo.bar);
+/// ```
+#[macro_export]
+macro_rules! project {
+    ($scope:expr, $local:ident $(. $field:tt)+) => {
+        $crate::__project($scope, &$local, |v| &v $(. $field)+)
+    };
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn project<'a, P: Trace, T: Trace, F: MemberField<T>>(
+    scope: &mut Scope<'a>,
+    parent: &Local<'_, P>,
+    field: impl FnOnce(&P) -> &F,
+) -> F::Projected<'a> {
+    let local = read_field(parent, field).map(|member| unsafe { member.in_scope(scope) });
+    F::projected(local)
+}
+
+/// Read a reference field of a rooted object.
+///
+/// Anything referenced from the object's own memory is traced through it,
+/// and references are checked when they are stored, so the field refers
+/// to a live object. The closure may only return references borrowed from
+/// the object or `'static` ones, and the latter are rejected by checking
+/// the field's location.
+#[inline]
+fn read_field<P: Trace, T: Trace, F: MemberField<T>>(
+    parent: &Local<'_, P>,
+    field: impl FnOnce(&P) -> &F,
+) -> Option<Member<T>> {
+    let parent: &P = parent;
+    let field = field(parent);
+    assert_field_of(parent, field.slot());
+    let member = field.member()?;
+    member.check_heap();
+    Some(member)
+}
+
+/// Panics if `slot` is not one of the references of `parent`.
+///
+/// Slots within the memory of `parent` itself are accepted right away.
+/// Others, such as the elements of a `Vec`, are looked for by tracing
+/// `parent`, which takes time proportional to its number of references.
+#[inline]
+fn assert_field_of<P: Trace, T: Trace>(parent: &P, slot: &Cell<Ptr<T>>) {
+    let start = parent as *const P as usize;
+    let end = start + std::mem::size_of::<P>();
+    let addr = slot as *const Cell<Ptr<T>> as usize;
+    if start <= addr && addr + std::mem::size_of::<Cell<Ptr<T>>>() <= end {
+        return;
+    }
+
+    let mut tracer = Tracer::find(slot as *const Cell<Ptr<T>> as *const ());
+    unsafe { parent.trace(&mut tracer) };
+    assert!(tracer.found(), "field is not a part of the parent object");
+}
+
+/// A handle which is not bound to any `Scope`.
+///
+/// The object is kept alive for as long as at least one clone of the
+/// handle exists, which makes it possible to store references to objects
+/// in Rust data structures, callbacks, caches, etc.
+///
+/// To access the object, it must first be turned into a `Local`
+/// using [`Global::to_local`].
+///
+/// ## Example
+/// ```rust,ignore
+/// let global = {
+///   let s = &mut Scope::new(s);
+///   let foo = Local::new(s, Foo { v: 100 });
+///   Global::new(s, &foo)
+/// };
+///
+/// s.collect_all();
+///
+/// let foo = global.to_local(s);
+/// println!("{}", foo.v);
+/// ```
+pub struct Global<T: Trace> {
+    slot: Rc<SharedSlot>,
+    _type: PhantomData<T>,
+}
+
+impl<T: Trace> Global<T> {
+    pub fn new(scope: &Scope<'_>, value: &Local<'_, T>) -> Self {
+        unsafe {
+            let slot = Rc::new(Cell::new(GcCell::erase(*value.slot)));
+            (*scope.scope_data).globals.push(Rc::downgrade(&slot));
+            Global {
+                slot,
+                _type: PhantomData,
+            }
+        }
+    }
+
+    /// Panics if the `Gc` which this handle belongs to has been dropped.
+    pub fn to_local<'a>(&self, scope: &mut Scope<'a>) -> Local<'a, T> {
+        let ptr = self.slot.get();
+        assert!(!ptr.is_null(), "global handle outlived its `Gc`");
+        unsafe { Local::alloc(scope.scope_data, ptr as Ptr<T>) }
+    }
+}
+
+impl<T: Trace> Clone for Global<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            _type: PhantomData,
+        }
+    }
+}
+
+/// A handle which does not keep its object alive.
+///
+/// Once the object is freed, the handle is cleared, and [`Weak::upgrade`]
+/// returns `None`.
+///
+/// See [`WeakMember`] for the form which may be stored in objects.
+pub struct Weak<T: Trace> {
+    slot: Rc<SharedSlot>,
+    _type: PhantomData<T>,
+}
+
+impl<T: Trace> Weak<T> {
+    pub fn new(scope: &Scope<'_>, value: &Local<'_, T>) -> Self {
+        unsafe {
+            let slot = Rc::new(Cell::new(GcCell::erase(*value.slot)));
+            (*scope.scope_data).weaks.push(Rc::downgrade(&slot));
+            Weak {
+                slot,
+                _type: PhantomData,
+            }
+        }
+    }
+
+    /// Returns `None` if the object has been freed.
+    pub fn upgrade<'a>(&self, scope: &mut Scope<'a>) -> Option<Local<'a, T>> {
+        let ptr = self.slot.get();
+        if ptr.is_null() {
+            return None;
+        }
+        unsafe { Some(Local::alloc(scope.scope_data, ptr as Ptr<T>)) }
+    }
+}
+
+impl<T: Trace> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        Self {
+            slot: self.slot.clone(),
+            _type: PhantomData,
+        }
+    }
+}
+
+/// A weak reference which may be stored in an object.
+///
+/// It is cleared when the object it refers to is freed. Just like `Member`,
+/// it must only be accessed when reachable through a root.
+///
+/// Like [`MemberCell::set`], `set` goes through the write barrier, so a
+/// `WeakMember` in an old object is also cleared by minor collections.
+pub struct WeakMember<T: Trace> {
+    pub(crate) ptr: Cell<Ptr<T>>,
+    pub(crate) heap: Cell<HeapTag>,
+}
+
+impl<T: Trace> WeakMember<T> {
+    #[inline]
+    pub fn new(value: Member<T>) -> Self {
+        Self {
+            ptr: Cell::new(value.as_raw()),
+            heap: Cell::new(value.heap),
+        }
+    }
+
+    /// Store `value` into this field of `parent`.
+    ///
+    /// Panics if the field is not a part of `parent`.
+    #[inline]
+    pub fn set<P: Trace>(&self, scope: &Scope<'_>, parent: &Local<'_, P>, value: &Local<'_, T>) {
+        assert_field_of::<P, T>(parent, &self.ptr);
+        scope.write_barrier(parent, value);
+        self.ptr.set(unsafe { *value.slot });
+        self.heap.set(HeapTag::new(scope.heap()));
+    }
+
+    /// Returns `None` if the object has been freed.
+    ///
+    /// ## Safety
+    /// `self` must be reachable, and must not have been read from its object
+    /// before a [`Gc::compact`](crate::Gc::compact), see [`Member::get`].
+    #[inline]
+    pub unsafe fn upgrade<'a>(&self, scope: &mut Scope<'a>) -> Option<Local<'a, T>> {
+        let ptr = self.ptr.get();
+        if ptr.is_null() {
+            return None;
+        }
+        self.heap.get().check(scope.heap());
+        Some(Local::alloc(scope.scope_data, ptr))
+    }
+}
+
+impl<T: Trace> Default for WeakMember<T> {
+    fn default() -> Self {
+        Self {
+            ptr: Cell::new(null_mut()),
+            heap: Cell::new(HeapTag::default()),
+        }
+    }
+}
+
 pub trait ParentScope<'scope>: private::Sealed {
     fn scope_data(&self) -> *mut ScopeData;
     fn allocator(&self) -> *mut Allocator;
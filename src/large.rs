@@ -0,0 +1,140 @@
+//! Large object space.
+//!
+//! Objects above `Config::large_object_threshold`, or which do not fit into
+//! any size class, are not stored in pages. Each of them gets a mapping of
+//! its own, which is swept separately from the pages, and never moved. The
+//! memory of a freed object is returned to the OS right away.
+//!
+//! Mappings are made in whole OS pages. On platforms without `mmap`, the
+//! memory comes from the global allocator instead, with a page-aligned layout.
+
+use crate::alloc::Data;
+use crate::alloc::GcCell;
+use std::alloc::handle_alloc_error;
+use std::alloc::Layout;
+use std::collections::BTreeMap;
+
+struct LargeObject {
+    ptr: *mut GcCell<Data>,
+    /// Size of the mapping, a multiple of the OS page size.
+    size: usize,
+}
+
+impl Drop for LargeObject {
+    /// The object is not dropped, only its memory is freed.
+    fn drop(&mut self) {
+        unsafe { unmap(self.ptr as *mut u8, self.size) }
+    }
+}
+
+/// Size of the mapping for an object with `layout`.
+pub(crate) fn mapping_size(layout: Layout) -> usize {
+    layout.size().next_multiple_of(page_size())
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+/// Map `size` bytes of zeroed memory, or return null if that fails.
+#[cfg(unix)]
+unsafe fn map(size: usize) -> *mut u8 {
+    let ptr = libc::mmap(
+        std::ptr::null_mut(),
+        size,
+        libc::PROT_READ | libc::PROT_WRITE,
+        libc::MAP_PRIVATE | libc::MAP_ANON,
+        -1,
+        0,
+    );
+    if ptr == libc::MAP_FAILED {
+        return std::ptr::null_mut();
+    }
+    ptr as *mut u8
+}
+
+#[cfg(unix)]
+unsafe fn unmap(ptr: *mut u8, size: usize) {
+    libc::munmap(ptr as *mut libc::c_void, size);
+}
+
+#[cfg(not(unix))]
+unsafe fn map(size: usize) -> *mut u8 {
+    std::alloc::alloc_zeroed(Layout::from_size_align(size, page_size()).unwrap())
+}
+
+#[cfg(not(unix))]
+unsafe fn unmap(ptr: *mut u8, size: usize) {
+    std::alloc::dealloc(ptr, Layout::from_size_align(size, page_size()).unwrap())
+}
+
+#[derive(Default)]
+pub(crate) struct LargeObjectSpace {
+    /// By address, so that references can be checked quickly.
+    objects: BTreeMap<*mut GcCell<Data>, LargeObject>,
+}
+
+impl LargeObjectSpace {
+    /// Allocate memory for an object with `layout`.
+    ///
+    /// Returns the memory, and its size.
+    pub(crate) fn alloc(&mut self, layout: Layout) -> (*mut u8, usize) {
+        assert!(
+            layout.align() <= page_size(),
+            "objects may not be aligned to more than a page"
+        );
+        let size = mapping_size(layout);
+        let ptr = unsafe { map(size) };
+        if ptr.is_null() {
+            handle_alloc_error(Layout::from_size_align(size, page_size()).unwrap());
+        }
+        trace!(ptr = ptr, size = size; "large alloc");
+
+        let ptr = ptr as *mut GcCell<Data>;
+        self.objects.insert(ptr, LargeObject { ptr, size });
+        (ptr as *mut u8, size)
+    }
+
+    /// Whether `ptr` points to one of the objects.
+    pub(crate) fn contains(&self, ptr: *mut GcCell<Data>) -> bool {
+        self.objects.contains_key(&ptr)
+    }
+
+    pub(crate) fn objects(&self) -> impl Iterator<Item = *mut GcCell<Data>> + '_ {
+        self.objects.keys().copied()
+    }
+
+    /// Iterate over all objects, along with the size of their allocation.
+    pub(crate) fn objects_with_size(
+        &self,
+    ) -> impl Iterator<Item = (*mut GcCell<Data>, usize)> + '_ {
+        self.objects
+            .values()
+            .map(|object| (object.ptr, object.size))
+    }
+
+    /// Free every object for which `f` returns `false`.
+    ///
+    /// Returns the number of freed bytes.
+    ///
+    /// ## Safety
+    /// The objects must not be used after they are freed.
+    pub(crate) unsafe fn retain(&mut self, mut f: impl FnMut(*mut GcCell<Data>) -> bool) -> usize {
+        let mut freed_bytes = 0;
+        self.objects.retain(|_, object| {
+            if f(object.ptr) {
+                return true;
+            }
+            GcCell::drop_data(object.ptr);
+            freed_bytes += object.size;
+            false
+        });
+        freed_bytes
+    }
+}
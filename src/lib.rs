@@ -8,47 +8,598 @@ pub use gc_derive::Trace;
 mod macros;
 
 mod alloc;
+mod compact;
+mod ephemeron;
 mod handle;
+mod large;
+mod page;
+mod verify;
+
+pub use ephemeron::EphemeronTable;
+
+pub use handle::EscapeScope;
+pub use handle::Global;
+pub use handle::Local;
+pub use handle::LocalMut;
+pub use handle::Member;
+pub use handle::MemberCell;
+pub use handle::MemberField;
+pub use handle::OptionMemberCell;
+pub use handle::ParentScope;
+pub use handle::Scope;
+pub use handle::Weak;
+pub use handle::WeakMember;
+
+#[doc(hidden)]
+pub use handle::project as __project;
 
 use alloc::Allocator;
+use alloc::Data;
 use alloc::GcCell;
+use compact::Forwarding;
+use ephemeron::Ephemeron;
+use handle::HeapTag;
 use handle::ScopeData;
+use std::cell::Cell;
 use std::cell::UnsafeCell;
+use std::collections::HashMap;
 use std::ptr::null_mut;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+use verify::Verifier;
 
 #[allow(clippy::missing_safety_doc)]
 /// Implementations of this trait should be derived using the `trace` attribute macro if possible.
+///
+/// Derived implementations forbid implementing `Drop` for the type, see [`Finalize`].
 pub unsafe trait Trace: 'static {
     /// ## Safety
-    /// The implementation _must_ trace all interior references.
-    unsafe fn trace(&self);
+    /// The implementation _must_ pass `tracer` to the `trace` implementations
+    /// of all interior references.
+    unsafe fn trace(&self, tracer: &mut Tracer);
 }
 
+/// Decides what happens to the references visited by [`Trace::trace`].
+///
+/// The collector uses it to mark objects, and to update references to
+/// objects which were moved. `Trace` implementations should only pass it
+/// on to the `trace` implementations of their fields.
+pub struct Tracer {
+    kind: TracerKind,
+
+    /// The object being traced, if it is known.
+    holder: *mut GcCell<Data>,
+}
+
+#[derive(Clone, Copy)]
+enum TracerKind {
+    /// Shade referenced objects, and record weak references and ephemerons.
+    Mark(*const Allocator),
+    /// Rewrite references to objects which were moved by compaction.
+    Update(*const Forwarding),
+    /// Check that referenced objects are live, see [`Config::verify`].
+    Verify(*mut Verifier),
+    /// Clear weak references to objects which are about to be freed.
+    ClearWeak(*const Allocator),
+    /// Look for a reference slot, and record whether it was found.
+    Find(*const (), bool),
+    /// Check that referenced objects are live, before a new object refers to them.
+    Check(*const Allocator),
+}
+
+impl Tracer {
+    #[inline]
+    pub(crate) fn mark(allocator: &Allocator) -> Self {
+        Self {
+            kind: TracerKind::Mark(allocator),
+            holder: null_mut(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn update(forwarding: &Forwarding) -> Self {
+        Self {
+            kind: TracerKind::Update(forwarding),
+            holder: null_mut(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn verify(verifier: &mut Verifier) -> Self {
+        Self {
+            kind: TracerKind::Verify(verifier),
+            holder: null_mut(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn clear_weak(allocator: &Allocator) -> Self {
+        Self {
+            kind: TracerKind::ClearWeak(allocator),
+            holder: null_mut(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn check(allocator: &Allocator) -> Self {
+        Self {
+            kind: TracerKind::Check(allocator),
+            holder: null_mut(),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn find(slot: *const ()) -> Self {
+        Self {
+            kind: TracerKind::Find(slot, false),
+            holder: null_mut(),
+        }
+    }
+
+    /// Whether a `find` tracer visited its slot.
+    #[inline]
+    pub(crate) fn found(&self) -> bool {
+        matches!(self.kind, TracerKind::Find(_, true))
+    }
+
+    /// Record `slot` as found if it is the one a `find` tracer looks for.
+    #[inline]
+    fn find_slot<T>(&mut self, slot: &Cell<T>) {
+        if let TracerKind::Find(target, found) = &mut self.kind {
+            *found |= std::ptr::eq(slot as *const Cell<T> as *const (), *target);
+        }
+    }
+
+    /// Trace the fields of `object`.
+    ///
+    /// While marking, `object` is recorded if it holds any weak references,
+    /// see `Allocator::weak_holders`.
+    ///
+    /// ## Safety
+    /// `object` must point to a live object.
+    #[inline]
+    pub(crate) unsafe fn trace_object(&mut self, object: *mut GcCell<Data>) {
+        self.holder = object;
+        GcCell::trace(object, self);
+        self.holder = null_mut();
+    }
+
+    /// Panics if `heap` is not the id of the `Gc` being collected.
+    ///
+    /// Only marking dereferences the objects it visits, and new objects
+    /// are checked before they can be marked. The other kinds of tracers
+    /// only compare or replace the pointers, so they do not check again.
+    #[inline]
+    fn check_heap(&self, heap: HeapTag) {
+        if let TracerKind::Mark(allocator) | TracerKind::Check(allocator) = self.kind {
+            heap.check(unsafe { (*allocator).id })
+        }
+    }
+
+    /// Visit a strong reference.
+    #[inline]
+    unsafe fn visit<T: Trace>(&mut self, slot: &Cell<*mut GcCell<T>>) {
+        if let TracerKind::Check(allocator) = self.kind {
+            return (*allocator).check_live(slot.get());
+        }
+        let slot = erase_slot(slot);
+        match self.kind {
+            TracerKind::Mark(allocator) => (*allocator).shade(slot.get()),
+            TracerKind::Update(forwarding) => (*forwarding).update(slot),
+            TracerKind::Verify(verifier) => (*verifier).edge(slot.get()),
+            TracerKind::ClearWeak(_) => {}
+            TracerKind::Find(..) => self.find_slot(slot),
+            TracerKind::Check(_) => unreachable!(),
+        }
+    }
+
+    /// Visit the slot of a `WeakMember`.
+    #[inline]
+    unsafe fn visit_weak<T: Trace>(&mut self, slot: &Cell<*mut GcCell<T>>) {
+        if let TracerKind::Check(allocator) = self.kind {
+            if !slot.get().is_null() {
+                (*allocator).check_live(slot.get());
+            }
+            return;
+        }
+        let slot = erase_slot(slot);
+        match self.kind {
+            TracerKind::Mark(allocator) => {
+                // the values of ephemeron tables are traced without a holder,
+                // and the tables are traced again on their own instead
+                let holders = &mut *(*allocator).weak_holders.get();
+                if !self.holder.is_null() && holders.last() != Some(&self.holder) {
+                    holders.push(self.holder);
+                }
+            }
+            TracerKind::Update(forwarding) => (*forwarding).update(slot),
+            TracerKind::Verify(verifier) => (*verifier).weak_edge(slot.get()),
+            TracerKind::ClearWeak(allocator) => {
+                let cell = slot.get();
+                if !cell.is_null() && (*allocator).is_dead(cell) {
+                    slot.set(null_mut());
+                }
+            }
+            TracerKind::Find(..) => self.find_slot(slot),
+            TracerKind::Check(_) => unreachable!(),
+        }
+    }
+
+    /// Visit an ephemeron table.
+    #[inline]
+    pub(crate) unsafe fn visit_ephemeron(&mut self, table: &(dyn Ephemeron + 'static)) {
+        match self.kind {
+            TracerKind::Mark(allocator) => (*(*allocator).ephemerons.get()).push(table),
+            TracerKind::Update(forwarding) => table.update(&*forwarding, self),
+            TracerKind::Verify(_) | TracerKind::Find(..) => table.visit_entries(self),
+            // tables are only created empty, and their entries are checked on insertion
+            TracerKind::ClearWeak(_) | TracerKind::Check(_) => {}
+        }
+    }
+}
+
+/// Marker for types which are statically known to contain no references
+/// to the garbage collected heap.
+///
+/// Fields of such types may be skipped in a derived `Trace` implementation
+/// using `#[trace(skip)]`.
+///
+/// The orphan rule prevents implementing this for types from other crates,
+/// such as `regex::Regex`. Wrap them in a local newtype, and implement it
+/// for that instead. Unlike `#[trace(unsafe_ignore)]`, the claim is then
+/// made once, next to the type, instead of on every field which uses it.
+///
+/// ## Example
+/// ```rust,ignore
+/// struct Pattern(regex::Regex);
+///
+/// // `Regex` does not refer to any managed objects
+/// unsafe impl NoTrace for Pattern {}
+///
+/// #[derive(Trace)]
+/// struct Rule {
+///     #[trace(skip)]
+///     pattern: Pattern,
+///     action: Member<Action>,
+/// }
+/// ```
+///
+/// ## Safety
+/// The type must not contain any `Member`, or any other type which holds
+/// a reference to a managed object.
+pub unsafe trait NoTrace {}
+
+/// Cleanup logic for managed objects.
+///
+/// Types which derive `Trace` may not implement `Drop`, because the collector
+/// frees dead objects in no particular order: by the time an object is dropped,
+/// the objects its `Member` fields point to may already be freed. Instead, use
+/// `#[trace(finalize)]` and implement this trait.
+///
+/// `finalize` is called when the object is dropped, either by the collector
+/// or normally. It may release any resources the object owns, but it must not
+/// access other managed objects through its `Member` fields. Cleanup which
+/// needs other objects should use [`Scope::register_finalizer`] instead.
+///
+/// ## Example
+/// ```rust,ignore
+/// #[derive(Trace)]
+/// #[trace(finalize)]
+/// struct Socket {
+///     fd: RawFd,
+///     peer: Member<Socket>,
+/// }
+///
+/// impl Finalize for Socket {
+///     fn finalize(&mut self) {
+///         close(self.fd);
+///     }
+/// }
+/// ```
+///
+/// Implementing `Drop` instead is rejected by the derive:
+/// ```compile_fail,E0119
+/// # use gc::*;
+/// #[derive(Trace)]
+/// struct Socket {
+///     peer: Member<Socket>,
+/// }
+///
+/// impl Drop for Socket {
+///     fn drop(&mut self) {}
+/// }
+/// ```
+pub trait Finalize {
+    fn finalize(&mut self);
+}
+
+#[doc(hidden)]
+#[inline(always)]
+pub fn __assert_no_trace<T: NoTrace + ?Sized>(_: &T) {}
+
+/// The garbage collected heap.
+///
+/// Only one `Gc` may be live on a thread at a time, and none of the handle
+/// types may be sent to other threads. A `Local` can't outlive the `Scope` it
+/// was created in, so it is always a handle of the live `Gc`. Handles which may
+/// outlive their heap (`Global` and `Weak`) are cleared when it is dropped.
+///
+/// [`Member`] and the other reference fields are not bound to any scope, so they
+/// may be kept until after their heap was dropped, and stored into objects of the
+/// next one. Storing one into a new object of another heap panics, because its
+/// object is not live there. With debug assertions, each of them also records the
+/// id of the `Gc` it belongs to, and using one in another heap panics once it is
+/// traced or dereferenced.
 pub struct Gc {
     pub(crate) scope_data: UnsafeCell<ScopeData>,
     pub(crate) allocator: UnsafeCell<Allocator>,
 }
 
+/// Identifies a `Gc` for as long as the process runs.
+pub(crate) type HeapId = u64;
+
+/// Id of the next `Gc` to be created. Starts at `1`, because `0` means none.
+static NEXT_HEAP_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Id of the live `Gc` on this thread, or `0` if there is none.
+    static LIVE: Cell<HeapId> = const { Cell::new(0) };
+}
+
+/// Id of the live `Gc` on this thread, or `0` if there is none.
+#[inline]
+pub(crate) fn live_heap() -> HeapId {
+    LIVE.get()
+}
+
 impl Gc {
+    /// Panics if there is already a live `Gc` on the current thread.
     pub fn new(config: Config) -> Self {
+        assert!(
+            LIVE.get() == 0,
+            "only one `Gc` may be live on a thread at a time"
+        );
+        let id = NEXT_HEAP_ID.fetch_add(1, Ordering::Relaxed);
+        LIVE.set(id);
+
         Self {
             scope_data: UnsafeCell::new(ScopeData::new()),
-            allocator: UnsafeCell::new(Allocator::new(config.allocator)),
+            allocator: UnsafeCell::new(Allocator::new(config.allocator, id)),
         }
     }
 
+    /// Perform one incremental step of a GC cycle.
+    ///
+    /// See [`Scope::collect`].
     #[inline]
     pub fn collect(&mut self) {
-        // TODO: incremental collection
-        self.collect_all()
+        gc_step(self.scope_data.get(), self.allocator.get());
+        unsafe { run_finalizers(self.scope_data.get(), self.allocator.get()) };
     }
 
+    /// Trigger a _full_ GC cycle.
     #[inline]
     pub fn collect_all(&mut self) {
-        gc(self.scope_data.get(), self.allocator.get())
+        gc(self.scope_data.get(), self.allocator.get());
+        unsafe { run_finalizers(self.scope_data.get(), self.allocator.get()) };
+    }
+
+    /// Collect only the young generation.
+    ///
+    /// See [`Scope::collect_young`].
+    #[inline]
+    pub fn collect_young(&mut self) {
+        gc_young(self.scope_data.get(), self.allocator.get());
+        unsafe { run_finalizers(self.scope_data.get(), self.allocator.get()) };
+    }
+
+    /// Run a full GC cycle, and then move all live objects
+    /// into as few pages as possible.
+    ///
+    /// This reduces fragmentation of long-running heaps. All objects are
+    /// promoted to the old generation.
+    ///
+    /// Dereferencing a `Local` produces plain references into the heap, which
+    /// can't be updated when objects move, so this is only available while
+    /// no `Scope` exists. The same applies to a `Member` kept outside of the
+    /// heap, which still points to the old location of its object afterwards.
+    pub fn compact(&mut self) {
+        let scope_data = self.scope_data.get();
+        let allocator = self.allocator.get();
+        unsafe {
+            gc(scope_data, allocator);
+            // queued finalizers create handles, so they run after the move
+            compact::compact(scope_data, allocator);
+            if (*allocator).config.verify {
+                verify::verify(scope_data, allocator);
+            }
+            run_finalizers(scope_data, allocator);
+        }
+    }
+
+    /// Current heap statistics.
+    pub fn stats(&self) -> Stats {
+        let allocator = unsafe { &*self.allocator.get() };
+        let scope_data = unsafe { &*self.scope_data.get() };
+
+        let live_objects = allocator.objects.get();
+        let live_bytes = allocator.bytes.get();
+        let allocated_objects = allocator.total_objects.get();
+        let allocated_bytes = allocator.total_bytes.get();
+        Stats {
+            live_objects,
+            live_bytes,
+            large_objects: allocator.large_objects.get(),
+            large_bytes: allocator.large_bytes.get(),
+            allocated_objects,
+            allocated_bytes,
+            freed_objects: allocated_objects - live_objects as u64,
+            freed_bytes: allocated_bytes - live_bytes as u64,
+            collections: allocator.collections.get(),
+            minor_collections: allocator.minor_collections.get(),
+            handle_blocks: scope_data.block_count(),
+        }
+    }
+
+    /// Register a callback which is called at the start and end
+    /// of each phase of every collection.
+    ///
+    /// Callbacks are called in the order they were registered,
+    /// and can't be unregistered.
+    pub fn on_event(&mut self, f: impl FnMut(&Event) + 'static) {
+        self.allocator
+            .get_mut()
+            .listeners
+            .get_mut()
+            .push(Box::new(f));
+    }
+
+    /// Number of objects and bytes in the heap for each type,
+    /// sorted by bytes in descending order.
+    ///
+    /// Like [`Stats::live_objects`], this includes unreachable objects
+    /// which have not been swept yet. Run a full collection first
+    /// to only see reachable ones.
+    pub fn histogram(&self) -> Vec<HistogramEntry> {
+        let allocator = unsafe { &*self.allocator.get() };
+        let heap = unsafe { &*allocator.heap.get() };
+        let large = unsafe { &*allocator.large.get() };
+
+        let objects = heap
+            .pages()
+            .flat_map(|page| page.objects().map(|cell| (cell, page.cell_size())))
+            .chain(large.objects_with_size());
+
+        let mut entries = HashMap::<&'static str, HistogramEntry>::new();
+        for (cell, size) in objects {
+            let type_name = unsafe { GcCell::type_name(cell) };
+            let entry = entries.entry(type_name).or_insert(HistogramEntry {
+                type_name,
+                count: 0,
+                bytes: 0,
+            });
+            entry.count += 1;
+            entry.bytes += size;
+        }
+
+        let mut entries = entries.into_values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            b.bytes
+                .cmp(&a.bytes)
+                .then_with(|| a.type_name.cmp(b.type_name))
+        });
+        entries
     }
 }
 
+/// A phase of a collection, see [`Gc::on_event`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    /// A collection is about to start.
+    ///
+    /// An incremental cycle starts at an earlier allocation than the one which
+    /// finishes it, so the rest of its events follow after some time.
+    Start,
+
+    /// Marking is done.
+    ///
+    /// For incremental cycles, this only covers the final marking
+    /// at the end of the cycle.
+    MarkEnd,
+
+    /// Unreachable objects have been freed.
+    SweepEnd,
+
+    /// Unused handle blocks have been freed, which ends the collection.
+    BlocksFreed,
+}
+
+/// Passed to the callbacks registered with [`Gc::on_event`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct Event {
+    pub kind: EventKind,
+
+    /// Whether this is a minor collection, which only collects the young generation.
+    pub minor: bool,
+
+    /// Time spent in the phase which just ended, or zero for [`EventKind::Start`].
+    pub elapsed: Duration,
+
+    /// Number of objects freed by this collection, or zero before [`EventKind::SweepEnd`].
+    pub freed_objects: usize,
+
+    /// Number of bytes freed by this collection, or zero before [`EventKind::SweepEnd`].
+    pub freed_bytes: usize,
+
+    /// Number of objects which have not been freed yet, see [`Stats::live_objects`].
+    pub live_objects: usize,
+
+    /// Number of bytes used by the objects which have not been freed yet.
+    pub live_bytes: usize,
+}
+
+/// The objects of one type in the heap, see [`Gc::histogram`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistogramEntry {
+    /// Name of the type, as returned by [`std::any::type_name`].
+    pub type_name: &'static str,
+
+    /// Number of objects of this type.
+    pub count: usize,
+
+    /// Number of bytes used by objects of this type,
+    /// including their headers and the unused part of their cells.
+    pub bytes: usize,
+}
+
+/// Heap statistics, see [`Gc::stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Number of objects which have not been freed yet.
+    ///
+    /// This includes unreachable objects which have not been swept yet.
+    pub live_objects: usize,
+
+    /// Number of bytes used by the live objects, including their headers
+    /// and the unused part of their cells.
+    pub live_bytes: usize,
+
+    /// Number of live objects in the large object space.
+    ///
+    /// These are also counted in `live_objects`.
+    pub large_objects: usize,
+
+    /// Number of bytes used by live objects in the large object space.
+    ///
+    /// These are also counted in `live_bytes`.
+    pub large_bytes: usize,
+
+    /// Number of objects allocated since the `Gc` was created.
+    pub allocated_objects: u64,
+
+    /// Number of bytes allocated since the `Gc` was created.
+    pub allocated_bytes: u64,
+
+    /// Number of objects freed since the `Gc` was created.
+    pub freed_objects: u64,
+
+    /// Number of bytes freed since the `Gc` was created.
+    pub freed_bytes: u64,
+
+    /// Number of completed full collections.
+    pub collections: u64,
+
+    /// Number of completed minor collections.
+    pub minor_collections: u64,
+
+    /// Number of handle blocks currently allocated.
+    pub handle_blocks: usize,
+}
+
 impl Default for Gc {
     fn default() -> Self {
         Self::new(Config::default())
@@ -57,14 +608,8 @@ impl Default for Gc {
 
 impl Drop for Gc {
     fn drop(&mut self) {
-        unsafe {
-            let mut current = self.allocator.get_mut().head.get();
-            while !current.is_null() {
-                let prev = GcCell::get_prev(current);
-                GcCell::free(current);
-                current = prev;
-            }
-        }
+        // objects are freed when the allocator is dropped
+        LIVE.set(0);
     }
 }
 
@@ -78,6 +623,88 @@ impl Config {
         self.allocator.stress = v;
         self
     }
+
+    /// Maximum number of objects traced by a single call to `collect`.
+    ///
+    /// Panics if `v` is zero.
+    pub fn mark_slice(mut self, v: usize) -> Self {
+        assert!(v > 0, "mark slice must not be empty");
+        self.allocator.mark_slice = v;
+        self
+    }
+
+    /// Factor by which the heap may grow after a cycle before
+    /// allocation triggers the next one.
+    ///
+    /// Panics if `v` is not greater than `1.0`.
+    pub fn growth_factor(mut self, v: f64) -> Self {
+        assert!(v > 1.0, "growth factor must be greater than 1.0");
+        self.allocator.growth_factor = v;
+        self
+    }
+
+    /// Heap size in bytes below which allocation never triggers a cycle.
+    pub fn min_heap(mut self, v: usize) -> Self {
+        self.allocator.min_heap = v;
+        self
+    }
+
+    /// Number of bytes which may be allocated in the nursery
+    /// before allocation triggers a minor collection.
+    ///
+    /// `0` disables automatic minor collections.
+    pub fn nursery_size(mut self, v: usize) -> Self {
+        self.allocator.nursery_size = v;
+        self
+    }
+
+    /// Number of objects in each chunk of the mark stack.
+    ///
+    /// The mark stack grows and shrinks by one chunk at a time.
+    ///
+    /// Panics if `v` is zero.
+    pub fn mark_chunk_size(mut self, v: usize) -> Self {
+        assert!(v > 0, "mark stack chunks must not be empty");
+        self.allocator.mark_chunk_size = v;
+        self
+    }
+
+    /// Objects larger than this many bytes are allocated in the large object space.
+    ///
+    /// Each large object has a memory mapping of its own, rounded up to whole
+    /// OS pages, which is returned to the OS as soon as the object is freed.
+    /// Large objects are never moved by [`Gc::compact`]. Objects which do not
+    /// fit into the largest size class (2 KiB, including the header) are always
+    /// large, so raising the threshold above that has no effect.
+    pub fn large_object_threshold(mut self, v: usize) -> Self {
+        self.allocator.large_object_threshold = v;
+        self
+    }
+
+    /// Hard limit on the size of the heap in bytes.
+    ///
+    /// An allocation which would grow the heap beyond this first runs a full
+    /// collection, and fails if there is still not enough room afterwards.
+    /// [`Local::try_new`] returns an error in that case, other ways of
+    /// allocating panic.
+    pub fn heap_limit(mut self, v: usize) -> Self {
+        self.allocator.heap_limit = v;
+        self
+    }
+
+    /// Verify the heap after every collection.
+    ///
+    /// All objects reachable from the roots are walked, and every reference
+    /// found by tracing them, as well as every handle, is checked to point at
+    /// an object which was not freed. If one does not, this panics with the
+    /// type of the object which holds the reference.
+    ///
+    /// This is slow, and meant for finding bugs in `Trace` implementations
+    /// and missing write barriers.
+    pub fn verify(mut self, v: bool) -> Self {
+        self.allocator.verify = v;
+        self
+    }
 }
 
 #[allow(clippy::derivable_impls)]
@@ -89,173 +716,574 @@ impl Default for Config {
     }
 }
 
+/// The heap limit would have been exceeded by an allocation,
+/// even after a full collection.
+///
+/// See [`Config::heap_limit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocError {
+    size: usize,
+    limit: usize,
+}
+
+impl AllocError {
+    /// Size of the object which could not be allocated, including its header.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The heap limit which would have been exceeded.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+}
+
+impl std::fmt::Display for AllocError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "allocating {} bytes would exceed the heap limit of {} bytes",
+            self.size, self.limit
+        )
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+/// Run a full GC cycle.
+///
+/// If an incremental cycle is already in progress, it is finished instead.
 #[inline(never)]
 pub(crate) fn gc(scope_data: *mut ScopeData, allocator: *mut Allocator) {
-    mark(scope_data);
-    sweep(allocator);
+    unsafe {
+        if !(*allocator).marking.get() {
+            start_marking(scope_data, allocator);
+        }
+        finish(scope_data, allocator);
+    }
+}
 
+/// Run one incremental step of a GC cycle.
+///
+/// Starts a new cycle if none is in progress, and then traces at most
+/// `mark_slice` objects. Once there is no more grey objects left,
+/// the cycle is completed in the same step.
+#[inline(never)]
+pub(crate) fn gc_step(scope_data: *mut ScopeData, allocator: *mut Allocator) {
     unsafe {
-        (*scope_data).free_unused_blocks();
+        if !(*allocator).marking.get() {
+            start_marking(scope_data, allocator);
+        }
+        if mark(allocator, (*allocator).config.mark_slice) {
+            finish(scope_data, allocator);
+        }
     }
 }
 
-fn mark(scope_data: *mut ScopeData) {
-    debug!("mark phase");
+/// Run a minor collection, which only collects the nursery.
+///
+/// The roots of a minor collection are the handles and the remembered set.
+/// Surviving objects are promoted to the old generation.
+///
+/// Minor collections cannot run while an incremental cycle is in progress,
+/// so in that case this performs an incremental step instead.
+#[inline(never)]
+pub(crate) fn gc_young(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    unsafe {
+        if (*allocator).marking.get() {
+            return gc_step(scope_data, allocator);
+        }
 
-    let scope_data = unsafe { &mut *scope_data };
-    for cell in scope_data.iter() {
-        if cell.is_null() {
-            debug!("null handle");
-            continue;
+        debug!(young_bytes = (*allocator).young_bytes.get(); "minor collection");
+
+        let allocator_ref = &*allocator;
+        let mut events = Events::new(allocator_ref, true);
+        events.emit(EventKind::Start);
+
+        allocator_ref.minor.set(true);
+        mark_roots(scope_data, allocator);
+        // remembered objects are old, which are never shaded by
+        // a minor collection, so their fields are traced directly
+        let mut tracer = Tracer::mark(allocator_ref);
+        for cell in std::mem::take(&mut *allocator_ref.remembered.get()) {
+            GcCell::set_remembered(cell, false);
+            tracer.trace_object(cell);
         }
+        mark_all(allocator);
+        queue_finalizers(allocator);
+        clear_weak_refs(scope_data, allocator);
+        allocator_ref.minor.set(false);
+        events.emit(EventKind::MarkEnd);
+
+        (events.freed_objects, events.freed_bytes) = sweep(allocator, true);
+        allocator_ref.young_bytes.set(0);
+        allocator_ref
+            .minor_collections
+            .set(allocator_ref.minor_collections.get() + 1);
+        events.emit(EventKind::SweepEnd);
 
-        unsafe { GcCell::trace(cell) };
-    }
-}
+        (*scope_data).free_unused_blocks();
+        events.emit(EventKind::BlocksFreed);
 
-#[cfg(__verbose_gc)]
-struct DebugPtr<T>(*const T);
-#[cfg(__verbose_gc)]
-impl<T> std::fmt::Debug for DebugPtr<T> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:p}", self.0)
+        if allocator_ref.config.verify {
+            verify::verify(scope_data, allocator);
+        }
     }
 }
 
-fn sweep(allocator: *mut Allocator) {
-    debug!("sweep phase");
-
-    // the core of the algorithm is:
-    //
-    // ```
-    // if marked(ptr):
-    //   unmark(ptr)
-    // else:
-    //   free(ptr)
-    // ```
-    //
-    // every object has a pointer to the object allocated before it,
-    // which we use to traverse all objects. during sweep, we also
-    // have to maintain it by updating the `prev` pointers of all
-    // live objects to the next live object found when traversing
-    // the list.
-    //
-    // before:
-    //   null <- A <- B <- C <- D <- E
-    //   mark:   1    1    0    0    1
-    //
-    // after:
-    //   null <- A <- B <- E
-    //   mark:   0    0    0
-
-    unsafe {
-        #[allow(unused_variables)]
-        let mut freed_n = 0;
+unsafe fn start_marking(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    debug!("start marking");
 
-        let allocator = &*allocator;
+    Events::new(&*allocator, false).emit(EventKind::Start);
+    (*allocator).marking.set(true);
+    mark_roots(scope_data, allocator);
+}
 
-        // last marked object, which will have its `prev` pointer updated as we sweep dead objects
-        let mut last_live = null_mut();
+/// Complete the current cycle.
+///
+/// Handles are not covered by the write barrier, so the roots must
+/// be re-scanned before the final (non-incremental) mark.
+unsafe fn finish(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    debug!("finish");
+
+    let mut events = Events::new(&*allocator, false);
+
+    mark_roots(scope_data, allocator);
+    mark_all(allocator);
+    queue_finalizers(allocator);
+    clear_weak_refs(scope_data, allocator);
+    events.emit(EventKind::MarkEnd);
+
+    // dead objects must not stay in the remembered set
+    (*(*allocator).remembered.get()).retain(|&cell| GcCell::is_marked(cell));
+
+    (events.freed_objects, events.freed_bytes) = sweep(allocator, false);
+    (*allocator).marking.set(false);
+    (*allocator).update_threshold();
+    (*allocator)
+        .collections
+        .set((*allocator).collections.get() + 1);
+    events.emit(EventKind::SweepEnd);
+
+    (*scope_data).free_unused_blocks();
+    events.emit(EventKind::BlocksFreed);
+
+    if (*allocator).config.verify {
+        verify::verify(scope_data, allocator);
+    }
+}
 
-        // the new allocator head, which will be set to the first marked object
-        let mut new_head = None;
+/// Reports the phases of a collection to the callbacks registered with [`Gc::on_event`].
+struct Events<'a> {
+    allocator: &'a Allocator,
+    minor: bool,
 
-        // current pointer in the linked list
-        let mut current = allocator.head.get();
+    /// When the previous phase ended, or `None` if there are no callbacks.
+    last: Option<Instant>,
 
-        while !current.is_null() {
-            let prev = GcCell::get_prev(current);
-            let marked = GcCell::is_marked(current);
+    freed_objects: usize,
+    freed_bytes: usize,
+}
 
-            debug!("marked={marked}, current={current:p}, prev={prev:p}, last_live={last_live:p}");
+impl<'a> Events<'a> {
+    fn new(allocator: &'a Allocator, minor: bool) -> Self {
+        let listening = unsafe { !(*allocator.listeners.get()).is_empty() };
+        Self {
+            allocator,
+            minor,
+            last: listening.then(Instant::now),
+            freed_objects: 0,
+            freed_bytes: 0,
+        }
+    }
 
-            if marked {
-                GcCell::set_mark(current, false);
-                last_live = current;
-                if new_head.is_none() {
-                    new_head = Some(current);
-                }
-            } else {
-                if !last_live.is_null() {
-                    GcCell::set_prev(last_live, prev);
-                }
-                GcCell::free(current);
-                freed_n += 1;
-            }
-            current = prev;
+    fn emit(&mut self, kind: EventKind) {
+        let Some(last) = self.last else {
+            return;
+        };
+        let now = Instant::now();
+        self.last = Some(now);
+
+        let event = Event {
+            kind,
+            minor: self.minor,
+            elapsed: match kind {
+                EventKind::Start => Duration::ZERO,
+                _ => now - last,
+            },
+            freed_objects: self.freed_objects,
+            freed_bytes: self.freed_bytes,
+            live_objects: self.allocator.objects.get(),
+            live_bytes: self.allocator.bytes.get(),
+        };
+        for listener in unsafe { (*self.allocator.listeners.get()).iter_mut() } {
+            listener(&event);
         }
+    }
+}
+
+fn mark_roots(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    debug!("mark roots");
 
-        match new_head {
-            Some(ptr) => allocator.head.set(ptr),
-            // we've freed every object
-            None => allocator.head.set(null_mut()),
+    let scope_data = unsafe { &mut *scope_data };
+    let allocator = unsafe { &*allocator };
+    for cell in scope_data.iter() {
+        if cell.is_null() {
+            trace!("null handle");
+            continue;
         }
 
-        debug!("freed {freed_n} objects");
+        unsafe { allocator.shade(cell) };
+    }
+
+    for slot in scope_data.globals() {
+        unsafe { allocator.shade(slot.get()) };
+    }
+
+    for finalizer in unsafe { (*allocator.finalization_queue.get()).iter() } {
+        unsafe { allocator.shade(finalizer.cell) };
     }
 }
 
-unsafe impl<T: Trace> Trace for crate::handle::Member<T> {
-    #[inline(always)]
-    unsafe fn trace(&self) {
-        GcCell::trace(GcCell::erase(self.ptr).cast_const())
+/// Trace at most `budget` grey objects.
+///
+/// Returns `true` if there are no grey objects left.
+fn mark(allocator: *mut Allocator, budget: usize) -> bool {
+    debug!("mark phase");
+
+    let allocator = unsafe { &*allocator };
+    let mut tracer = Tracer::mark(allocator);
+    let mut traced = 0;
+    while traced < budget {
+        let Some(cell) = allocator.pop_grey() else {
+            break;
+        };
+        unsafe { tracer.trace_object(cell) };
+        traced += 1;
     }
+
+    debug!(traced = traced; "mark done");
+    unsafe { (*allocator.grey.get()).is_empty() }
 }
 
-unsafe impl<T: Trace> Trace for Option<T> {
-    #[inline]
-    unsafe fn trace(&self) {
-        if let Some(v) = self {
-            v.trace();
+/// Trace all grey objects, and everything reachable through ephemerons.
+///
+/// The values of ephemerons with marked keys may mark more keys, so
+/// this repeats until tracing them does not produce any new grey objects.
+fn mark_all(allocator: *mut Allocator) {
+    loop {
+        mark(allocator, usize::MAX);
+
+        debug!("trace ephemerons");
+        let allocator = unsafe { &*allocator };
+        let mut tracer = Tracer::mark(allocator);
+        // tracing the values may find more tables
+        let mut i = 0;
+        while let Some(table) = unsafe { (&*allocator.ephemerons.get()).get(i).copied() } {
+            unsafe { (*table).trace_values(allocator, &mut tracer) };
+            i += 1;
+        }
+
+        if unsafe { (*allocator.grey.get()).is_empty() } {
+            break;
         }
     }
 }
 
-unsafe impl<T: Trace> Trace for std::cell::RefCell<T> {
-    #[inline]
-    unsafe fn trace(&self) {
-        self.borrow().trace();
+/// Clear all weak references to objects which are about to be freed,
+/// and remove ephemerons with dead keys.
+unsafe fn clear_weak_refs(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    debug!("clear weak refs");
+
+    let allocator = &*allocator;
+    let mut tracer = Tracer::clear_weak(allocator);
+    for table in (*allocator.ephemerons.get()).drain(..) {
+        (*table).remove_dead(allocator);
+        // the values of the remaining entries may hold weak references
+        (*table).trace_values(allocator, &mut tracer);
+    }
+
+    for holder in (*allocator.weak_holders.get()).drain(..) {
+        tracer.trace_object(holder);
+    }
+
+    for slot in (*scope_data).weaks() {
+        let cell = slot.get();
+        if !cell.is_null() && allocator.is_dead(cell) {
+            slot.set(null_mut());
+        }
     }
 }
 
-unsafe impl<T: Trace> Trace for &'static T {
-    #[inline]
-    unsafe fn trace(&self) {
-        (**self).trace();
+/// Move the finalizers of dead objects to the finalization queue.
+///
+/// Their objects, and everything reachable from them, are resurrected
+/// so that they survive until the finalizer has run. Weak references to
+/// them are not cleared.
+unsafe fn queue_finalizers(allocator: *mut Allocator) {
+    let allocator_ref = &*allocator;
+    let finalizers = &mut *allocator_ref.finalizers.get();
+    // all dead objects are found before any are resurrected, otherwise
+    // an object reachable from another finalizable object would be missed
+    let (dead, live): (Vec<_>, Vec<_>) = std::mem::take(finalizers)
+        .into_iter()
+        .partition(|finalizer| allocator_ref.is_dead(finalizer.cell));
+    *finalizers = live;
+
+    if dead.is_empty() {
+        return;
+    }
+
+    debug!(finalizers = dead.len(); "queue finalizers");
+    let queue = &mut *allocator_ref.finalization_queue.get();
+    for finalizer in dead {
+        allocator_ref.shade(finalizer.cell);
+        queue.push_back(finalizer);
     }
+    mark_all(allocator);
 }
 
-macro_rules! impl_null_trace {
-    ($($ty:ty),* $(,)?) => {
-        $(
-            unsafe impl $crate::Trace for $ty {
-                #[inline(always)]
-                unsafe fn trace(&self) {}
-            }
-        )*
+/// Run all queued finalizers, in the order they were queued.
+///
+/// Finalizers may allocate and trigger more collections, which only
+/// add to the queue being drained here.
+pub(crate) unsafe fn run_finalizers(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    let allocator_ref = &*allocator;
+    if allocator_ref.finalizing.replace(true) {
+        return;
     }
+
+    while let Some(finalizer) = (*allocator_ref.finalization_queue.get()).pop_front() {
+        let mut scope = Scope::new_raw(scope_data, allocator);
+        (finalizer.run)(&mut scope, finalizer.cell);
+    }
+    // opening a scope resets the tombstone, so the handles of the
+    // finalized objects no longer keep them alive
+    drop(Scope::new_raw(scope_data, allocator));
+
+    allocator_ref.finalizing.set(false);
 }
 
-impl_null_trace! {
-    i8, i16, i32, i64, i128, isize,
-    u8, u16, u32, u64, u128, usize,
-    f32, f64,
-    char,
-    bool,
-    str, String,
+/// Erase the type of a reference slot.
+#[inline]
+fn erase_slot<T: Trace>(slot: &Cell<*mut GcCell<T>>) -> &Cell<*mut GcCell<Data>> {
+    unsafe { &*(slot as *const Cell<_> as *const Cell<*mut GcCell<Data>>) }
 }
 
-macro_rules! impl_trace_collections {
-    ($(
+#[cfg(__verbose_gc)]
+struct DebugPtr<T>(*const T);
+#[cfg(__verbose_gc)]
+impl<T> std::fmt::Debug for DebugPtr<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:p}", self.0)
+    }
+}
+
+/// Free all unmarked objects, and unmark the rest.
+///
+/// A minor sweep only visits pages which received objects since the last
+/// minor collection. It leaves old objects alone, and promotes all surviving
+/// young objects to the old generation.
+///
+/// The large object space is swept separately, after the pages.
+///
+/// Returns the number of freed objects and bytes.
+fn sweep(allocator: *mut Allocator, minor: bool) -> (usize, usize) {
+    debug!(minor = minor; "sweep phase");
+
+    unsafe {
+        let allocator = &*allocator;
+        let heap = &mut *allocator.heap.get();
+
+        let mut freed_objects = 0;
+        let mut freed_bytes = 0;
+
+        for page in heap.pages_mut() {
+            if minor && !page.has_young {
+                continue;
+            }
+
+            let freed = page.retain(|cell| sweep_object(cell, minor));
+            if minor {
+                page.has_young = false;
+            }
+
+            freed_objects += freed;
+            freed_bytes += freed * page.cell_size();
+        }
+        heap.free_empty_pages();
+
+        let mut freed_large = 0;
+        let freed_large_bytes = (*allocator.large.get()).retain(|cell| {
+            let live = sweep_object(cell, minor);
+            if !live {
+                freed_large += 1;
+            }
+            live
+        });
+        allocator
+            .large_objects
+            .set(allocator.large_objects.get() - freed_large);
+        allocator
+            .large_bytes
+            .set(allocator.large_bytes.get() - freed_large_bytes);
+        freed_objects += freed_large;
+        freed_bytes += freed_large_bytes;
+
+        allocator
+            .objects
+            .set(allocator.objects.get() - freed_objects);
+        allocator.bytes.set(allocator.bytes.get() - freed_bytes);
+
+        debug!(
+            freed_objects = freed_objects,
+            freed_bytes = freed_bytes,
+            freed_large = freed_large;
+            "sweep done"
+        );
+
+        (freed_objects, freed_bytes)
+    }
+}
+
+/// Whether `cell` survives the current sweep.
+#[inline]
+unsafe fn sweep_object(cell: *mut GcCell<Data>, minor: bool) -> bool {
+    if minor && GcCell::is_old(cell) {
+        return true;
+    }
+    if !GcCell::is_marked(cell) {
+        return false;
+    }
+    GcCell::set_mark(cell, false);
+    if minor {
+        GcCell::set_old(cell, true);
+    }
+    true
+}
+
+unsafe impl<T: Trace> Trace for crate::handle::Member<T> {
+    #[inline(always)]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        tracer.check_heap(self.heap);
+        tracer.visit(&self.ptr)
+    }
+}
+
+unsafe impl<T: Trace> Trace for crate::handle::MemberCell<T> {
+    #[inline(always)]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        tracer.check_heap(self.heap.get());
+        tracer.visit(&self.ptr)
+    }
+}
+
+unsafe impl<T: Trace> Trace for crate::handle::OptionMemberCell<T> {
+    #[inline(always)]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        if !self.ptr.get().is_null() {
+            tracer.check_heap(self.heap.get());
+            tracer.visit(&self.ptr)
+        }
+    }
+}
+
+unsafe impl<T: Trace> Trace for crate::handle::WeakMember<T> {
+    #[inline(always)]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        if !self.ptr.get().is_null() {
+            tracer.check_heap(self.heap.get());
+        }
+        tracer.visit_weak(&self.ptr)
+    }
+}
+
+unsafe impl<T: Trace> Trace for Option<T> {
+    #[inline]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        if let Some(v) = self {
+            v.trace(tracer);
+        }
+    }
+}
+
+/// Only implemented for contents without references, because a reference
+/// stored through a `RefCell` would not go through the write barrier. Use
+/// [`MemberCell`] and [`OptionMemberCell`] for mutable references instead.
+unsafe impl<T: NoTrace + 'static> Trace for std::cell::RefCell<T> {
+    #[inline(always)]
+    unsafe fn trace(&self, _: &mut Tracer) {}
+}
+
+unsafe impl<T: Trace> Trace for &'static T {
+    #[inline]
+    unsafe fn trace(&self, tracer: &mut Tracer) {
+        (**self).trace(tracer);
+    }
+}
+
+macro_rules! impl_null_trace {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            unsafe impl $crate::Trace for $ty {
+                #[inline(always)]
+                unsafe fn trace(&self, _: &mut $crate::Tracer) {}
+            }
+
+            unsafe impl $crate::NoTrace for $ty {}
+        )*
+    }
+}
+
+impl_null_trace! {
+    i8, i16, i32, i64, i128, isize,
+    u8, u16, u32, u64, u128, usize,
+    f32, f64,
+    char,
+    bool,
+    str, String,
+    std::fs::File,
+    std::path::Path, std::path::PathBuf,
+    std::time::Duration, std::time::Instant, std::time::SystemTime,
+}
+
+macro_rules! impl_no_trace_generic {
+    ($(<$($T:ident),*> for $ty:ty ;)+) => {
+        $(
+            unsafe impl<$($T : $crate::NoTrace,)*> $crate::NoTrace for $ty {}
+        )+
+    }
+}
+
+impl_no_trace_generic! {
+    <T> for Option<T>;
+    <T> for Box<T>;
+    <T> for std::rc::Rc<T>;
+    <T> for std::sync::Arc<T>;
+    <T> for Cell<T>;
+    <T> for std::cell::RefCell<T>;
+    <T> for Vec<T>;
+    <T> for std::collections::VecDeque<T>;
+    <K, V> for std::collections::HashMap<K, V>;
+    <K, V> for std::collections::BTreeMap<K, V>;
+    <T> for std::collections::HashSet<T>;
+    <T> for std::collections::BTreeSet<T>;
+}
+
+unsafe impl<T: NoTrace> NoTrace for [T] {}
+unsafe impl<T: NoTrace, const N: usize> NoTrace for [T; N] {}
+
+macro_rules! impl_trace_collections {
+    ($(
         $(<$($T:ident),*>)? as ($($v:ident),*) for $ty:ty ;
     )+) => {
         $(
             #[allow(unused_parens)]
             unsafe impl $(<$($T : $crate::Trace,)*>)? $crate::Trace for $ty {
                 #[inline]
-                unsafe fn trace(&self) {
+                unsafe fn trace(&self, tracer: &mut $crate::Tracer) {
                     for ($($v),*) in self {
-                        $($v.trace();)*
+                        $($v.trace(tracer);)*
                     }
                 }
             }
@@ -276,9 +1304,9 @@ impl_trace_collections! {
 
 unsafe impl<T: Trace, const N: usize> Trace for [T; N] {
     #[inline]
-    unsafe fn trace(&self) {
+    unsafe fn trace(&self, tracer: &mut Tracer) {
         for v in self {
-            v.trace();
+            v.trace(tracer);
         }
     }
 }
@@ -307,13 +1335,13 @@ macro_rules! all_the_tuples {
 
 macro_rules! impl_trace_tuple {
     ($($field:ident,)*) => {
-        #[allow(non_snake_case, unused_parens)]
+        #[allow(non_snake_case, unused_parens, unused_variables)]
         unsafe impl<$($field : $crate::Trace,)*> $crate::Trace for ($($field,)*) {
             #[inline]
-            unsafe fn trace(&self) {
+            unsafe fn trace(&self, tracer: &mut $crate::Tracer) {
                 let ($($field,)*) = self;
                 $(
-                    $field.trace();
+                    $field.trace(tracer);
                 )*
             }
         }
@@ -330,243 +1358,1512 @@ mod tests {
     use crate::handle::Member;
     use crate::handle::Scope;
     use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[derive(Trace)]
     struct Test {
         value: u32,
     }
 
+    /// Size of the mapping of a large object of type `T`.
+    fn large_mapping<T: Trace>() -> usize {
+        crate::large::mapping_size(std::alloc::Layout::new::<GcCell<T>>())
+    }
+
+    #[test]
+    fn simple() {
+        let mut cx = Gc::default();
+        let s = &mut Scope::new(&mut cx);
+        let v = Local::new(s, Test { value: 100 });
+        assert_eq!(v.value, 100);
+    }
+
+    #[test]
+    fn mark_and_sweep_0() {
+        // A, B and C are allocated into consecutive cells of one page:
+        //
+        // | A | B | C |
+        //   1   1   1
+        //
+        // sweeping walks the cells in order, finds all of them
+        // marked, and unmarks them:
+        //
+        // | A | B | C |
+        //   0   0   0
+
+        let cx = &mut Gc::default();
+        let s = &mut Scope::new(cx);
+        let a = Local::new(s, Test { value: 100 });
+        let b = Local::new(s, Test { value: 200 });
+        assert_eq!(a.value, 100);
+        assert_eq!(b.value, 200);
+        let c = Local::new(s, Test { value: 300 });
+        assert_eq!(c.value, 300);
+        s.collect();
+    }
+
+    #[test]
+    fn mark_and_sweep_1() {
+        // don't automatically trigger GC in this case
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        // | A | B | C | D | E | F |
+        //   1   0   1   0   0   1
+        //
+        // (0) A is marked, unmark
+        // (1) B is NOT marked, free, push its cell onto the free list
+        // (2) C is marked, unmark
+        // (3) D is NOT marked, free, push its cell onto the free list
+        // (4) E is NOT marked, free, push its cell onto the free list
+        // (5) F is marked, unmark
+        //
+        // | A | _ | C | _ | _ | F |    free: E -> D -> B
+        //   0       0           0
+
+        let s = &mut Scope::new(&mut cx);
+        let a = Local::new(s, Test { value: 100 });
+        {
+            let s = &mut Scope::new(s);
+            let _ = Local::new(s, Test { value: 200 });
+        }
+        let c = Local::new(s, Test { value: 300 });
+        {
+            let s = &mut Scope::new(s);
+            let _ = Local::new(s, Test { value: 400 });
+        }
+        {
+            let s = &mut Scope::new(s);
+            let _ = Local::new(s, Test { value: 500 });
+        }
+        let f = Local::new(s, Test { value: 600 });
+        assert_eq!(a.value + c.value + f.value, 1000);
+        s.collect();
+    }
+
+    #[derive(Trace)]
+    struct Compound {
+        data: Member<Test>,
+    }
+
+    #[test]
+    fn mark_and_sweep_2() {
+        // don't automatically trigger GC in this case
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let data = Local::new(s, Test { value: 100 }).to_member();
+        let v = Local::new(s, Compound { data });
+
+        let data = project!(s, v.data);
+        assert_eq!(data.value, 100);
+    }
+
+    #[derive(Trace)]
+    struct MutCompound {
+        data: MemberCell<Test>,
+    }
+
+    #[test]
+    fn member_cell() {
+        let mut cx = Gc::new(Config::default().stress(false).mark_slice(1));
+
+        let s = &mut Scope::new(&mut cx);
+        let v = {
+            let s = &mut EscapeScope::new(s);
+            let data = Local::new(s, Test { value: 100 }).to_member();
+            let v = Local::new(
+                s,
+                MutCompound {
+                    data: MemberCell::new(data),
+                },
+            );
+            s.escape(v)
+        };
+        reset_tombstone(s);
+
+        // `v` is black, `v.data` is grey
+        s.collect();
+
+        {
+            let s = &mut Scope::new(s);
+            let data = Local::new(s, Test { value: 200 });
+            v.data.set(s, &v, &data);
+        }
+        reset_tombstone(s);
+
+        s.collect_all();
+
+        let data = project!(s, v.data);
+        assert_eq!(data.value, 200);
+    }
+
+    thread_local! {
+        static COLLECTED_NODES: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+    }
+
+    #[derive(Trace)]
+    #[trace(finalize)]
+    struct Node {
+        prev: OptionMemberCell<Node>,
+        next: OptionMemberCell<Node>,
+        value: u32,
+    }
+
+    impl Node {
+        fn new<'a>(s: &mut Scope<'a>, value: u32) -> Local<'a, Node> {
+            Local::new(
+                s,
+                Node {
+                    prev: OptionMemberCell::default(),
+                    next: OptionMemberCell::default(),
+                    value,
+                },
+            )
+        }
+    }
+
+    impl Finalize for Node {
+        fn finalize(&mut self) {
+            COLLECTED_NODES.with_borrow_mut(|v| v.push(self.value));
+        }
+    }
+
+    fn node_join<'a>(s: &Scope<'_>, left: &Local<'a, Node>, right: &Local<'a, Node>) {
+        left.next.set(s, left, Some(right));
+        right.prev.set(s, right, Some(left));
+    }
+
+    fn node_rotate_right(node: &mut Local<'_, Node>) -> bool {
+        let next = node.next.get();
+        if let Some(next) = next {
+            unsafe {
+                next.move_to(node);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    fn node_rotate_left(node: &mut Local<'_, Node>) -> bool {
+        let prev = node.prev.get();
+        if let Some(prev) = prev {
+            unsafe {
+                prev.move_to(node);
+            }
+            true
+        } else {
+            false
+        }
+    }
+
+    #[test]
+    fn escape_value() {
+        let mut cx = Gc::default();
+
+        let outer = &mut Scope::new(&mut cx);
+        let node: Local<Node> = {
+            let inner = &mut EscapeScope::new(outer);
+            let node = Node::new(inner, 1);
+            inner.escape(node)
+        };
+
+        outer.collect();
+
+        let foo = Node::new(outer, 20);
+
+        assert_eq!(node.value, 1);
+        assert_eq!(foo.value, 20);
+    }
+
+    #[test]
+    fn tombstone_simple() {
+        let cx = &mut Gc::default();
+
+        let outer = &mut Scope::new(cx);
+        let ptr: *mut *mut GcCell<Node> = {
+            let inner = &mut Scope::new(outer);
+            let node = Node::new(inner, 1);
+
+            node.as_ptr()
+        };
+
+        outer.collect();
+
+        // should still be live because it's below the tombstone
+        // and we haven't re-used the handle yet
+        assert_eq!(unsafe { (*GcCell::data(*ptr)).value }, 1);
+
+        let _ = Node::new(outer, 2);
+
+        // we re-used the handle for the new node, so the reference
+        // is updated and so is the value stored in the object
+        assert_eq!(unsafe { (*GcCell::data(*ptr)).value }, 2);
+    }
+
+    #[test]
+    fn tombstone_nested() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let cx = &mut Gc::default();
+
+        let scope0 = &mut Scope::new(cx);
+        let _ = Node::new(scope0, 1);
+        {
+            let scope1 = &mut Scope::new(scope0);
+            let _ = Node::new(scope1, 2);
+            {
+                let scope2 = &mut Scope::new(scope1);
+                let _ = Node::new(scope2, 3);
+            }
+        }
+
+        scope0.collect();
+
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[3]));
+    }
+
+    #[test]
+    fn tombstone_next_block() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let cx = &mut Gc::default();
+
+        let outer = &mut Scope::new(cx);
+        let first = Node::new(outer, 1);
+        {
+            let inner = &mut Scope::new(outer);
+            for _ in 0..crate::handle::BLOCK_SIZE {
+                let _ = Node::new(inner, 2);
+            }
+        }
+        let second = Node::new(outer, 3);
+
+        // 1 handle apart
+        let distance = (second.as_ptr() as usize - first.as_ptr() as usize) / 8;
+        assert_eq!(distance, 1);
+    }
+
+    fn is_marking(s: &Scope<'_>) -> bool {
+        unsafe { (*s.allocator()).marking.get() }
+    }
+
+    /// Dropping an empty scope lowers the tombstone,
+    /// so that handles of previously dropped scopes are no longer roots.
+    fn reset_tombstone(s: &mut Scope<'_>) {
+        let _ = Scope::new(s);
+    }
+
+    fn node_chain<'a>(s: &mut Scope<'a>, n: u32) -> Local<'a, Node> {
+        let s = &mut EscapeScope::new(s);
+        let first = Node::new(s, 1);
+        let mut prev = first.in_scope(s);
+        for i in 2..=n {
+            let next = Node::new(s, i);
+            node_join(s, &prev, &next);
+            prev = next;
+        }
+        s.escape(first)
+    }
+
+    #[test]
+    fn incremental_steps() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false).mark_slice(1));
+
+        let s = &mut Scope::new(&mut cx);
+        let root = node_chain(s, 4);
+        reset_tombstone(s);
+
+        // each step traces one node of the chain,
+        // and the step which traces the last one sweeps
+        let mut steps = 0;
+        loop {
+            s.collect();
+            steps += 1;
+            if !is_marking(s) {
+                break;
+            }
+        }
+        assert_eq!(steps, 4);
+        assert_eq!(root.value, 1);
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+    }
+
+    #[test]
+    fn incremental_write_barrier() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false).mark_slice(1));
+
+        let s = &mut Scope::new(&mut cx);
+        let root = node_chain(s, 4);
+        reset_tombstone(s);
+
+        // `root` is now black
+        s.collect();
+        assert!(is_marking(s));
+
+        {
+            let s = &mut Scope::new(s);
+            // only reachable through `root`
+            let stored = Node::new(s, 5);
+            root.prev.set(s, &root, Some(&stored));
+
+            // not reachable at all
+            let _ = Node::new(s, 6);
+        }
+        reset_tombstone(s);
+
+        while is_marking(s) {
+            s.collect();
+        }
+
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[6]));
+        let prev = unsafe { root.prev.get().unwrap().get().value };
+        assert_eq!(prev, 5);
+    }
+
+    #[test]
+    fn collect_all_finishes_incremental_cycle() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false).mark_slice(1));
+
+        let s = &mut Scope::new(&mut cx);
+        let _ = node_chain(s, 4);
+        s.collect();
+        assert!(is_marking(s));
+
+        s.collect_all();
+        assert!(!is_marking(s));
+    }
+
+    #[test]
+    fn heap_size_trigger() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let node_size = std::alloc::Layout::new::<GcCell<Node>>().size();
+        let mut cx = Gc::new(Config::default().stress(false).min_heap(node_size * 16));
+
+        let s = &mut Scope::new(&mut cx);
+        let root = Node::new(s, 0);
+        for i in 1..=64 {
+            let s = &mut Scope::new(s);
+            let _ = Node::new(s, i);
+        }
+
+        // no manual `collect` call, but garbage was still freed
+        COLLECTED_NODES.with_borrow(|v| assert!(!v.is_empty()));
+        assert!(unsafe { (*s.allocator()).bytes.get() } <= node_size * 17);
+        assert_eq!(root.value, 0);
+    }
+
+    #[test]
+    fn minor_collection() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        {
+            let s = &mut Scope::new(s);
+            let old = Node::new(s, 1);
+            {
+                let s = &mut Scope::new(s);
+                let _ = Node::new(s, 2);
+            }
+            reset_tombstone(s);
+
+            // `1` is promoted, `2` is freed
+            s.collect_young();
+            COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[2]));
+            assert!(unsafe { GcCell::is_old(GcCell::erase(*old.as_ptr())) });
+        }
+        reset_tombstone(s);
+
+        // old objects are not freed by minor collections
+        s.collect_young();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[2]));
+
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[2, 1]));
+    }
+
+    #[test]
+    fn minor_collection_remembered_set() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let old = Node::new(s, 1);
+        s.collect_young();
+
+        {
+            let s = &mut Scope::new(s);
+            // only reachable through `old`
+            let young = Node::new(s, 2);
+            old.next.set(s, &old, Some(&young));
+        }
+        reset_tombstone(s);
+
+        s.collect_young();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+
+        let young = old.next.get().unwrap();
+        assert_eq!(unsafe { young.get().value }, 2);
+        assert!(unsafe { GcCell::is_old(GcCell::erase(young.as_raw())) });
+    }
+
+    #[test]
+    fn minor_collection_young_cycle() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        {
+            let s = &mut Scope::new(s);
+            let a = Node::new(s, 1);
+            let b = Node::new(s, 2);
+            node_join(s, &a, &b);
+            node_join(s, &b, &a);
+        }
+        reset_tombstone(s);
+
+        // stores between young objects do not make them roots
+        s.collect_young();
+        COLLECTED_NODES.with_borrow_mut(|v| {
+            v.sort();
+            assert_eq!(v, &[1, 2]);
+        });
+        assert_eq!(unsafe { (*s.allocator()).objects.get() }, 0);
+    }
+
+    #[test]
+    #[should_panic = "field is not a part of the parent object"]
+    fn member_cell_wrong_parent() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let a = Node::new(s, 1);
+        let b = Node::new(s, 2);
+        // the barrier would be applied to `b` instead of `a`
+        a.next.set(s, &b, Some(&b));
+    }
+
+    #[derive(Trace)]
+    struct Array {
+        items: Vec<MemberCell<Node>>,
+    }
+
+    fn node_array<'a>(s: &mut Scope<'a>, len: usize) -> Local<'a, Array> {
+        let s = &mut EscapeScope::new(s);
+        let node = Node::new(s, 0);
+        let items = (0..len).map(|_| MemberCell::new(node.to_member()));
+        let array = Local::new(
+            s,
+            Array {
+                items: items.collect(),
+            },
+        );
+        s.escape(array)
+    }
+
+    #[test]
+    fn member_cell_in_vec() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let array = node_array(s, 2);
+        // promote `array`, so that only the barrier keeps `1` alive
+        s.collect_young();
+
+        {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            array.items[1].set(s, &array, &node);
+        }
+        reset_tombstone(s);
+
+        s.collect_young();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+        assert_eq!(unsafe { array.items[0].get().get().value }, 0);
+        assert_eq!(unsafe { array.items[1].get().get().value }, 1);
+    }
+
+    #[test]
+    #[should_panic = "field is not a part of the parent object"]
+    fn member_cell_in_vec_wrong_parent() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let a = node_array(s, 1);
+        let b = node_array(s, 1);
+        let node = Node::new(s, 1);
+        a.items[0].set(s, &b, &node);
+    }
+
+    #[test]
+    fn global_handle() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let global = {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            Global::new(s, &node)
+        };
+        reset_tombstone(s);
+
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+
+        // clones share the same slot
+        let clone = global.clone();
+        drop(global);
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+
+        {
+            let s = &mut Scope::new(s);
+            assert_eq!(clone.to_local(s).value, 1);
+        }
+        reset_tombstone(s);
+
+        drop(clone);
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[1]));
+    }
+
+    #[test]
+    fn weak_handle() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let weak = {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            let weak = Weak::new(s, &node);
+            s.collect_all();
+            assert_eq!(weak.upgrade(s).unwrap().value, 1);
+            weak
+        };
+        reset_tombstone(s);
+
+        s.collect_all();
+        assert!(weak.upgrade(s).is_none());
+    }
+
+    #[derive(Trace)]
+    struct Cache {
+        entry: WeakMember<Node>,
+    }
+
+    #[test]
+    fn weak_member() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let cache = Local::new(
+            s,
+            Cache {
+                entry: WeakMember::default(),
+            },
+        );
+        // promote `cache`
+        s.collect_young();
+
+        {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            cache.entry.set(s, &cache, &node);
+        }
+        reset_tombstone(s);
+
+        // `1` is only weakly reachable from the old generation,
+        // so the minor collection frees it, and clears the entry
+        s.collect_young();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[1]));
+        assert!(unsafe { cache.entry.upgrade(s).is_none() });
+    }
+
+    /// Weak references which live outside of their object's own memory.
+    struct Observers {
+        list: RefCell<Vec<WeakMember<Node>>>,
+    }
+
+    unsafe impl Trace for Observers {
+        unsafe fn trace(&self, tracer: &mut Tracer) {
+            self.list.borrow().trace(tracer);
+        }
+    }
+
+    #[test]
+    fn weak_member_in_vec() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false).mark_slice(1));
+
+        let s = &mut Scope::new(&mut cx);
+        let observers = {
+            let s = &mut EscapeScope::new(s);
+            let node = Node::new(s, 1);
+            let observers = Local::new(
+                s,
+                Observers {
+                    list: RefCell::new(vec![WeakMember::new(node.to_member())]),
+                },
+            );
+            s.escape(observers)
+        };
+        reset_tombstone(s);
+
+        // the first step traces `observers`
+        s.collect();
+        let collections = unsafe { (*s.allocator()).collections.get() };
+
+        // the slot found by the first step is freed, and replaced
+        // by one which the cycle has not seen
+        let node = {
+            let s = &mut Scope::new(s);
+            Node::new(s, 2).to_member()
+        };
+        *observers.list.borrow_mut() = vec![WeakMember::new(node)];
+        reset_tombstone(s);
+
+        while unsafe { (*s.allocator()).collections.get() } == collections {
+            s.collect();
+        }
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[1, 2]));
+        assert!(unsafe { observers.list.borrow()[0].upgrade(s).is_none() });
+    }
+
+    #[test]
+    fn ephemeron_table() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let table = EphemeronTable::<Node, Member<Node>>::new(s);
+        let key = Node::new(s, 1);
+        {
+            let s = &mut Scope::new(s);
+
+            // live key, value only reachable through the table
+            let value = Node::new(s, 2);
+            table.insert(s, &key, value.to_member());
+
+            // the key is only reachable from the value of another entry
+            let dead_key = Node::new(s, 3);
+            let dead_value = Node::new(s, 4);
+            dead_value.next.set(s, &dead_value, Some(&dead_key));
+            table.insert(s, &dead_key, dead_value.to_member());
+        }
+        reset_tombstone(s);
+
+        s.collect_all();
+        COLLECTED_NODES.with_borrow_mut(|v| {
+            v.sort();
+            assert_eq!(v, &[3, 4]);
+        });
+        assert_eq!(table.len(), 1);
+        let value = unsafe { table.get(&key).unwrap().get().value };
+        assert_eq!(value, 2);
+    }
+
+    #[test]
+    fn ephemeron_chain() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let table = EphemeronTable::<Node, Member<Node>>::new(s);
+        let a = Node::new(s, 1);
+        {
+            let s = &mut Scope::new(s);
+
+            // `a` -> `b` -> `c`, inserted in reverse, so that
+            // it takes more than one round to reach a fixpoint
+            let b = Node::new(s, 2);
+            let c = Node::new(s, 3);
+            table.insert(s, &b, c.to_member());
+            table.insert(s, &a, b.to_member());
+        }
+        reset_tombstone(s);
+
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn ephemeron_table_minor() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let table = EphemeronTable::<Node, Member<Node>>::new(s);
+        let key = Node::new(s, 1);
+        // promote both
+        s.collect_young();
+
+        {
+            let s = &mut Scope::new(s);
+            let value = Node::new(s, 2);
+            table.insert(s, &key, value.to_member());
+        }
+        reset_tombstone(s);
+
+        // `2` is young, and only reachable through an old table
+        s.collect_young();
+        COLLECTED_NODES.with_borrow(|v| assert!(v.is_empty()));
+        let value = unsafe { table.get(&key).unwrap().get().value };
+        assert_eq!(value, 2);
+    }
+
+    #[test]
+    fn local_mut() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let head = node_chain(s, 4);
+
+        let mut node = LocalMut::new(s, &head);
+        let before = Node::new(s, 0);
+
+        // walking the list does not allocate any handles
+        let mut values = vec![node.value];
+        while node.follow(|node| &node.next) {
+            values.push(node.value);
+        }
+        assert_eq!(values, &[1, 2, 3, 4]);
+
+        let after = Node::new(s, 0);
+        let distance = (after.as_ptr() as usize - before.as_ptr() as usize) / 8;
+        assert_eq!(distance, 1);
+
+        node.set(&head);
+        assert_eq!(node.value, 1);
+
+        assert!(node.set_member(&head, |head| &head.next));
+        assert_eq!(node.value, 2);
+        assert!(!node.set_member(&head, |head| &head.prev));
+        assert_eq!(node.value, 2);
+    }
+
+    #[test]
+    #[should_panic = "field is not a part of the parent object"]
+    fn local_mut_foreign_field() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let head = node_chain(s, 2);
+        let other = Node::new(s, 3);
+        let leaked: &'static Member<Node> = Box::leak(Box::new(other.to_member()));
+
+        let mut node = LocalMut::new(s, &head);
+        node.set_member(&head, |_| leaked);
+    }
+
+    #[test]
+    #[should_panic = "found a reference to an object which is no longer live"]
+    fn local_mut_stale_member() {
+        let mut cx = Gc::new(Config::default().stress(false));
+
+        let s = &mut Scope::new(&mut cx);
+        let other = Local::new(s, Test { value: 0 });
+        let mut data = LocalMut::new(s, &other);
+        let member = stale_member(s);
+        // the parent can't be created, so the field is never read
+        let parent = Local::new(s, Compound { data: member });
+        data.set_member(&parent, |parent| &parent.data);
+    }
+
+    #[test]
+    #[should_panic = "only one `Gc` may be live on a thread at a time"]
+    fn one_gc_per_thread() {
+        let _a = Gc::default();
+        let _b = Gc::default();
+    }
+
+    #[test]
+    fn gc_after_drop() {
+        drop(Gc::default());
+        drop(Gc::default());
+    }
+
+    #[test]
+    #[should_panic = "global handle outlived its `Gc`"]
+    fn global_outlives_gc() {
+        let global = {
+            let mut cx = Gc::default();
+            let s = &mut Scope::new(&mut cx);
+            let v = Local::new(s, Test { value: 100 });
+            Global::new(s, &v)
+        };
+
+        let mut cx = Gc::default();
+        let s = &mut Scope::new(&mut cx);
+        let _ = global.to_local(s);
+    }
+
+    fn member_of_dropped_gc() -> Member<Test> {
+        let mut cx = Gc::default();
+        let s = &mut Scope::new(&mut cx);
+        Local::new(s, Test { value: 100 }).to_member()
+    }
+
     #[test]
-    fn simple() {
-        let mut cx = Gc::default();
+    #[cfg_attr(
+        debug_assertions,
+        should_panic = "found a reference to an object of another `Gc`"
+    )]
+    #[cfg_attr(
+        not(debug_assertions),
+        should_panic = "found a reference to an object which is no longer live"
+    )]
+    fn member_outlives_gc() {
+        let member = member_of_dropped_gc();
+
+        let mut cx = Gc::new(Config::default().stress(false));
         let s = &mut Scope::new(&mut cx);
-        let v = Local::new(s, Test { value: 100 });
-        assert_eq!(v.value, 100);
+        let _ = Local::new(s, Compound { data: member });
     }
 
     #[test]
-    fn mark_and_sweep_0() {
-        // null <- A <- B <- C
-        //         1    1    1
-        //
-        // (0) current: C, prev: B, C is live
-        // (1) current: B, prev: A, B is live
-        // (2) current: A, prev: null, A is live
-        //
-        // null <- A <- B <- C
-        //         0    0    0
+    fn reference_field_size() {
+        // the id of the `Gc` is only recorded with debug assertions
+        let words = if cfg!(debug_assertions) { 2 } else { 1 };
+        let size = words * std::mem::size_of::<usize>();
+        assert_eq!(std::mem::size_of::<Member<Test>>(), size);
+        assert_eq!(std::mem::size_of::<MemberCell<Test>>(), size);
+        assert_eq!(std::mem::size_of::<OptionMemberCell<Test>>(), size);
+        assert_eq!(std::mem::size_of::<WeakMember<Test>>(), size);
+    }
 
-        let cx = &mut Gc::default();
-        let s = &mut Scope::new(cx);
-        let a = Local::new(s, Test { value: 100 });
-        let b = Local::new(s, Test { value: 200 });
-        assert_eq!(a.value, 100);
-        assert_eq!(b.value, 200);
-        let c = Local::new(s, Test { value: 300 });
-        assert_eq!(c.value, 300);
-        s.collect();
+    /// Returns a `Member` to an object which was freed by a collection.
+    fn stale_member(s: &mut Scope<'_>) -> Member<Test> {
+        let member = {
+            let s = &mut Scope::new(s);
+            Local::new(s, Test { value: 100 }).to_member()
+        };
+        reset_tombstone(s);
+        s.collect_all();
+        member
     }
 
     #[test]
-    fn mark_and_sweep_1() {
-        // don't automatically trigger GC in this case
+    #[should_panic = "found a reference to an object which is no longer live"]
+    fn stale_member_freed() {
         let mut cx = Gc::new(Config::default().stress(false));
+        let s = &mut Scope::new(&mut cx);
+        let member = stale_member(s);
+        let _ = Local::new(s, Compound { data: member });
+    }
 
-        // null <- A <- B <- C <- D <- E <- F
-        //         1    0    1    0    0    1
-        //
-        // (0) current: F, prev: E      F is marked, unmark
-        // (1) current: E, prev: D      E is NOT marked, free, set F.prev = D
-        // (2) current: D, prev: C      D is NOT marked, free, set F.prev = C
-        // (3) current: C, prev: B      C is marked, unmark
-        // (4) current: B, prev: A      B is NOT marked, free, set C.prev = A
-        // (5) current: A, prev: null   A is marked, unmark
-        //
-        // null <- A <- C <- F
-        //         0    0    0
+    #[test]
+    #[should_panic = "found a reference to an object which is no longer live"]
+    fn stale_member_reused() {
+        let mut cx = Gc::new(Config::default().stress(false));
+        let s = &mut Scope::new(&mut cx);
+        // keeps the page of the freed object
+        let _ = Local::new(s, Test { value: 0 });
+        let member = stale_member(s);
+
+        // the freed cell is reused by an object of another type
+        let bytes = Local::new(s, [0u8; std::mem::size_of::<Test>()]);
+        assert_eq!(
+            bytes.to_member().as_raw() as usize,
+            member.as_raw() as usize
+        );
+        let _ = Local::new(s, Compound { data: member });
+    }
+
+    #[test]
+    #[should_panic = "found a reference to an object which is no longer live"]
+    fn stale_member_ephemeron() {
+        let mut cx = Gc::new(Config::default().stress(false));
+        let s = &mut Scope::new(&mut cx);
+        let table = EphemeronTable::<Test, Member<Test>>::new(s);
+        let key = Local::new(s, Test { value: 0 });
+        let member = stale_member(s);
+        table.insert(s, &key, member);
+    }
+
+    #[test]
+    fn finalizer() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+        let finalized = Rc::new(RefCell::new(vec![]));
 
         let s = &mut Scope::new(&mut cx);
-        let a = Local::new(s, Test { value: 100 });
         {
             let s = &mut Scope::new(s);
-            let _ = Local::new(s, Test { value: 200 });
+            let one = Node::new(s, 1);
+            let two = Node::new(s, 2);
+            node_join(s, &one, &two);
+            let finalized = finalized.clone();
+            s.register_finalizer(&one, move |s, one| {
+                let two = project!(s, one.next).unwrap();
+                finalized.borrow_mut().push((one.value, two.value));
+            });
         }
-        let c = Local::new(s, Test { value: 300 });
+        reset_tombstone(s);
+
+        // the finalizer runs, and its object survives until the next cycle
+        s.collect_all();
+        assert_eq!(*finalized.borrow(), [(1, 2)]);
+        assert!(COLLECTED_NODES.with_borrow(|v| v.is_empty()));
+
+        s.collect_all();
+        assert_eq!(*finalized.borrow(), [(1, 2)]);
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v.len(), 2));
+    }
+
+    #[test]
+    fn finalizer_resurrect() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+        let resurrected = Rc::new(RefCell::new(None));
+
+        let s = &mut Scope::new(&mut cx);
         {
             let s = &mut Scope::new(s);
-            let _ = Local::new(s, Test { value: 400 });
+            let node = Node::new(s, 1);
+            let resurrected = resurrected.clone();
+            s.register_finalizer(&node, move |s, node| {
+                *resurrected.borrow_mut() = Some(Global::new(s, &node));
+            });
         }
+        reset_tombstone(s);
+
+        s.collect_all();
+        s.collect_all();
+        assert!(COLLECTED_NODES.with_borrow(|v| v.is_empty()));
         {
             let s = &mut Scope::new(s);
-            let _ = Local::new(s, Test { value: 500 });
+            let node = resurrected.borrow().as_ref().unwrap().to_local(s);
+            assert_eq!(node.value, 1);
         }
-        let f = Local::new(s, Test { value: 600 });
-        assert_eq!(a.value + c.value + f.value, 1000);
-        s.collect();
-    }
 
-    #[derive(Trace)]
-    struct Compound {
-        data: Member<Test>,
+        // the finalizer only runs once
+        resurrected.borrow_mut().take();
+        reset_tombstone(s);
+        s.collect_all();
+        assert_eq!(COLLECTED_NODES.with_borrow(|v| v.clone()), [1]);
     }
 
     #[test]
-    fn mark_and_sweep_2() {
-        // don't automatically trigger GC in this case
+    fn finalizer_order() {
         let mut cx = Gc::new(Config::default().stress(false));
+        let finalized = Rc::new(RefCell::new(vec![]));
 
         let s = &mut Scope::new(&mut cx);
-        let data = Local::new(s, Test { value: 100 }).to_member();
-        let v = Local::new(s, Compound { data });
+        {
+            let s = &mut Scope::new(s);
+            for i in 0..4 {
+                let node = Node::new(s, i);
+                let finalized = finalized.clone();
+                s.register_finalizer(&node, move |_, node| {
+                    finalized.borrow_mut().push(node.value);
+                });
+            }
+        }
+        reset_tombstone(s);
 
-        let data = unsafe { v.data.in_scope(s) };
-        assert_eq!(data.value, 100);
+        s.collect_all();
+        assert_eq!(*finalized.borrow(), [0, 1, 2, 3]);
     }
 
-    thread_local! {
-        static COLLECTED_NODES: RefCell<Vec<u32>> = const { RefCell::new(vec![]) };
+    #[test]
+    fn finalizer_allocates() {
+        let mut cx = Gc::new(Config::default());
+        let finalized = Rc::new(RefCell::new(vec![]));
+
+        let s = &mut Scope::new(&mut cx);
+        {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            let finalized = finalized.clone();
+            s.register_finalizer(&node, move |s, node| {
+                // stress mode collects here
+                let next = Node::new(s, 2);
+                node_join(s, &node, &next);
+                finalized.borrow_mut().push(node.value);
+            });
+        }
+        reset_tombstone(s);
+
+        let _ = Node::new(s, 3);
+        assert_eq!(*finalized.borrow(), [1]);
     }
 
-    #[derive(Trace)]
-    struct Node {
-        prev: RefCell<Option<Member<Node>>>,
-        next: RefCell<Option<Member<Node>>>,
-        value: u32,
+    #[test]
+    fn finalizer_minor_collection() {
+        let mut cx = Gc::new(Config::default().stress(false));
+        let finalized = Rc::new(RefCell::new(vec![]));
+
+        let s = &mut Scope::new(&mut cx);
+        {
+            let s = &mut Scope::new(s);
+            let node = Node::new(s, 1);
+            let finalized = finalized.clone();
+            s.register_finalizer(&node, move |_, node| {
+                finalized.borrow_mut().push(node.value);
+            });
+        }
+        reset_tombstone(s);
+
+        s.collect_young();
+        assert_eq!(*finalized.borrow(), [1]);
     }
 
-    impl Node {
-        fn new<'a>(s: &mut Scope<'a>, value: u32) -> Local<'a, Node> {
-            Local::new(
+    #[test]
+    fn compact() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+
+        let mut cx = Gc::new(Config::default().stress(false));
+        let layout = std::alloc::Layout::new::<GcCell<Node>>();
+        let cells = (page::PAGE_SIZE / page::cell_size(layout).unwrap()) as u32;
+        let pages = |cx: &Gc| unsafe { (*(*cx.allocator.get()).heap.get()).pages().count() };
+
+        // `list` is left alone in its page by the garbage after it,
+        // while the next page is half full, so only `list` is moved
+        let (list, filler, key, table, cache, weak) = {
+            let s = &mut Scope::new(&mut cx);
+            let list = node_chain(s, 4);
+            for i in 0..cells {
+                let _ = Node::new(s, 100 + i);
+            }
+            let filler = node_chain(s, cells / 2);
+            let key = Node::new(s, 10);
+            let value = Node::new(s, 11);
+            let table = EphemeronTable::<Node, Member<Node>>::new(s);
+            table.insert(s, &key, value.to_member());
+            let cache = Local::new(
                 s,
-                Node {
-                    prev: RefCell::new(None),
-                    next: RefCell::new(None),
-                    value,
+                Cache {
+                    entry: WeakMember::new(list.to_member()),
                 },
+            );
+            (
+                Global::new(s, &list),
+                Global::new(s, &filler),
+                Global::new(s, &key),
+                Global::new(s, &table),
+                Global::new(s, &cache),
+                Weak::new(s, &list),
             )
-        }
+        };
+
+        let check = |cx: &mut Gc| {
+            let s = &mut Scope::new(cx);
+            let list = list.to_local(s);
+
+            let mut node = LocalMut::new(s, &list);
+            let mut values = vec![node.value];
+            while node.follow(|node| &node.next) {
+                values.push(node.value);
+            }
+            while node.follow(|node| &node.prev) {
+                values.push(node.value);
+            }
+            assert_eq!(values, [1, 2, 3, 4, 3, 2, 1]);
+
+            let upgraded = weak.upgrade(s).unwrap();
+            assert_eq!(upgraded.to_member().as_raw(), list.to_member().as_raw());
+
+            let cache = cache.to_local(s);
+            let entry = unsafe { cache.entry.upgrade(s).unwrap() };
+            assert_eq!(entry.to_member().as_raw(), list.to_member().as_raw());
+
+            let key = key.to_local(s);
+            let table = table.to_local(s);
+            let value = unsafe { table.get(&key).unwrap().get().value };
+            assert_eq!(value, 11);
+
+            list.to_member().as_raw()
+        };
+
+        let before = check(&mut cx);
+        let pages_before = pages(&cx);
+        cx.compact();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v.len(), cells as usize));
+        let after = check(&mut cx);
+        assert_ne!(before, after);
+        assert_eq!(pages(&cx), pages_before - 1);
+
+        // the remaining pages are dense, so nothing moves
+        cx.compact();
+        assert_eq!(check(&mut cx), after);
+
+        // moved objects are still dropped when they die
+        drop((list, filler, key, table, cache));
+        let _ = Scope::new(&mut cx);
+        cx.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v.len(), (cells + 4 + cells / 2 + 2) as usize));
     }
 
-    impl Drop for Node {
-        fn drop(&mut self) {
-            COLLECTED_NODES.with_borrow_mut(|v| v.push(self.value));
+    #[test]
+    fn heap_limit() {
+        const LIMIT: usize = 64 * 1024;
+
+        let mut cx = Gc::new(Config::default().stress(false).heap_limit(LIMIT));
+        let s = &mut Scope::new(&mut cx);
+
+        // garbage is collected to make room
+        for _ in 0..10_000 {
+            let s = &mut Scope::new(s);
+            Local::try_new(s, [0u8; 64]).unwrap();
         }
+
+        // live objects are not
+        let mut n = 0;
+        let e = loop {
+            match Local::try_new(s, [0u8; 64]) {
+                Ok(_) => n += 1,
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(n, LIMIT / 80);
+        assert_eq!((e.size(), e.limit()), (80, LIMIT));
     }
 
-    fn node_join<'a>(left: &Local<'a, Node>, right: &Local<'a, Node>) {
-        *left.next.borrow_mut() = Some(right.to_member());
-        *right.prev.borrow_mut() = Some(left.to_member());
+    #[test]
+    #[should_panic = "would exceed the heap limit"]
+    fn heap_limit_panics() {
+        let mut cx = Gc::new(Config::default().stress(false).heap_limit(1024));
+        let s = &mut Scope::new(&mut cx);
+        let _ = Local::new(s, [0u8; 1024]);
     }
 
-    fn node_rotate_right(node: &mut Local<'_, Node>) -> bool {
-        let next = *node.next.borrow();
-        if let Some(next) = next {
-            unsafe {
-                next.move_to(node);
-            }
-            true
-        } else {
-            false
-        }
+    #[test]
+    fn stats() {
+        let mut cx = Gc::new(Config::default().stress(false).large_object_threshold(256));
+        let stats = cx.stats();
+        assert_eq!((stats.live_objects, stats.allocated_objects), (0, 0));
+        let large_bytes = large_mapping::<[u8; 1024]>();
+
+        let large = {
+            let s = &mut Scope::new(&mut cx);
+            let _ = Local::new(s, [0u8; 64]);
+            let large = Local::new(s, [0u8; 1024]);
+            Global::new(s, &large)
+        };
+        let stats = cx.stats();
+        assert_eq!(
+            (stats.live_objects, stats.live_bytes),
+            (2, 80 + large_bytes)
+        );
+        assert_eq!((stats.large_objects, stats.large_bytes), (1, large_bytes));
+        assert_eq!(stats.handle_blocks, 1);
+
+        // drop the handles of the scope
+        let _ = Scope::new(&mut cx);
+        cx.collect_all();
+        let stats = cx.stats();
+        assert_eq!((stats.live_objects, stats.live_bytes), (1, large_bytes));
+        assert_eq!(
+            (stats.allocated_objects, stats.allocated_bytes),
+            (2, 80 + large_bytes as u64)
+        );
+        assert_eq!((stats.freed_objects, stats.freed_bytes), (1, 80));
+        assert_eq!((stats.collections, stats.minor_collections), (1, 0));
+
+        // promoted, so minor collections no longer free it
+        cx.collect_young();
+        drop(large);
+        cx.collect_young();
+        let stats = cx.stats();
+        assert_eq!((stats.live_objects, stats.live_bytes), (1, large_bytes));
+        assert_eq!((stats.collections, stats.minor_collections), (1, 2));
+
+        cx.collect_all();
+        let stats = cx.stats();
+        assert_eq!((stats.live_objects, stats.large_objects), (0, 0));
+        assert_eq!(
+            (stats.freed_objects, stats.freed_bytes),
+            (2, 80 + large_bytes as u64)
+        );
     }
 
-    fn node_rotate_left(node: &mut Local<'_, Node>) -> bool {
-        let prev = *node.prev.borrow();
-        if let Some(prev) = prev {
-            unsafe {
-                prev.move_to(node);
+    #[test]
+    fn histogram() {
+        let mut cx = Gc::new(Config::default().stress(false).large_object_threshold(256));
+        {
+            let s = &mut Scope::new(&mut cx);
+            for _ in 0..3 {
+                let _ = Local::new(s, [0u8; 64]);
             }
-            true
-        } else {
-            false
+            let _ = Local::new(s, [0u8; 1024]);
+            let _ = Local::new(s, 0u32);
+            let _ = Local::new(s, 1u32);
         }
+
+        let histogram = cx.histogram();
+        let entry = |type_name, count, bytes| HistogramEntry {
+            type_name,
+            count,
+            bytes,
+        };
+        assert_eq!(
+            histogram,
+            [
+                entry("[u8; 1024]", 1, large_mapping::<[u8; 1024]>()),
+                entry("[u8; 64]", 3, 3 * 80),
+                entry("u32", 2, 2 * 32),
+            ]
+        );
     }
 
     #[test]
-    fn escape_value() {
-        let mut cx = Gc::default();
+    fn events() {
+        let mut cx = Gc::new(Config::default().stress(false));
+        let events = Rc::new(RefCell::new(vec![]));
+        cx.on_event({
+            let events = events.clone();
+            move |e| {
+                let counts = (e.freed_objects, e.live_objects);
+                events.borrow_mut().push((e.kind, e.minor, counts));
+            }
+        });
 
-        let outer = &mut Scope::new(&mut cx);
-        let node: Local<Node> = {
-            let inner = &mut EscapeScope::new(outer);
-            let node = Node::new(inner, 1);
-            inner.escape(node)
+        let global = {
+            let s = &mut Scope::new(&mut cx);
+            let _ = Local::new(s, 0u32);
+            let _ = Local::new(s, 1u32);
+            let _ = Local::new(s, 2u32);
+            let object = Local::new(s, 3u32);
+            Global::new(s, &object)
         };
+        let _ = Scope::new(&mut cx);
+
+        cx.collect_young();
+        drop(global);
+        cx.collect_all();
+
+        use EventKind::*;
+        assert_eq!(
+            *events.borrow(),
+            [
+                (Start, true, (0, 4)),
+                (MarkEnd, true, (0, 4)),
+                (SweepEnd, true, (3, 1)),
+                (BlocksFreed, true, (3, 1)),
+                (Start, false, (0, 1)),
+                (MarkEnd, false, (0, 1)),
+                (SweepEnd, false, (1, 0)),
+                (BlocksFreed, false, (1, 0)),
+            ]
+        );
+    }
 
-        outer.collect();
+    // `tracing` takes precedence over `log`
+    #[cfg(all(feature = "log", not(feature = "tracing")))]
+    #[test]
+    fn log_records() {
+        use std::sync::Mutex;
 
-        let foo = Node::new(outer, 20);
+        static FREED: Mutex<Vec<String>> = Mutex::new(vec![]);
 
-        assert_eq!(node.value, 1);
-        assert_eq!(foo.value, 20);
+        struct Logger;
+        impl log::Log for Logger {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.target() == "gc"
+            }
+
+            fn log(&self, record: &log::Record) {
+                if record.args().to_string() == "sweep done" {
+                    let freed = record.key_values().get("freed_objects".into()).unwrap();
+                    FREED.lock().unwrap().push(freed.to_string());
+                }
+            }
+
+            fn flush(&self) {}
+        }
+
+        log::set_logger(&Logger).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut cx = Gc::new(Config::default().stress(false));
+        {
+            let s = &mut Scope::new(&mut cx);
+            let _ = Local::new(s, 0u32);
+        }
+        let _ = Scope::new(&mut cx);
+        cx.collect_all();
+
+        // other tests may be collecting at the same time
+        assert!(FREED.lock().unwrap().contains(&"1".to_owned()));
     }
 
     #[test]
-    fn tombstone_simple() {
-        let cx = &mut Gc::default();
-
-        let outer = &mut Scope::new(cx);
-        let ptr: *mut *mut GcCell<Node> = {
-            let inner = &mut Scope::new(outer);
-            let node = Node::new(inner, 1);
+    fn verify() {
+        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
 
-            node.as_ptr()
+        let mut cx = Gc::new(Config::default().stress(false).verify(true));
+        let (chain, table) = {
+            let s = &mut Scope::new(&mut cx);
+            let chain = node_chain(s, 10);
+            let table = EphemeronTable::<Node, Member<Node>>::new(s);
+            let key = Node::new(s, 11);
+            let value = Node::new(s, 12);
+            table.insert(s, &key, value.to_member());
+            table.insert(s, &chain, value.to_member());
+            (Global::new(s, &chain), Global::new(s, &table))
         };
+        let _ = Scope::new(&mut cx);
+
+        cx.collect_young();
+        cx.collect_all();
+        cx.compact();
+        COLLECTED_NODES.with_borrow_mut(|v| {
+            assert_eq!(v, &[11]);
+        });
+
+        drop(chain);
+        drop(table);
+        cx.collect_all();
+    }
 
-        outer.collect();
+    #[test]
+    #[should_panic = "Holder` at"]
+    fn verify_missing_barrier() {
+        struct Holder {
+            value: RefCell<Option<Member<u32>>>,
+        }
 
-        // should still be live because it's below the tombstone
-        // and we haven't re-used the handle yet
-        assert_eq!(unsafe { (*GcCell::data(*ptr)).value }, 1);
+        // allows storing a reference without going through the barrier
+        unsafe impl Trace for Holder {
+            unsafe fn trace(&self, tracer: &mut Tracer) {
+                self.value.borrow().trace(tracer);
+            }
+        }
 
-        let _ = Node::new(outer, 2);
+        let mut cx = Gc::new(Config::default().stress(false).verify(true));
+        let holder = {
+            let s = &mut Scope::new(&mut cx);
+            let holder = Local::new(
+                s,
+                Holder {
+                    value: RefCell::new(None),
+                },
+            );
+            Global::new(s, &holder)
+        };
+        cx.collect_young();
 
-        // we re-used the handle for the new node, so the reference
-        // is updated and so is the value stored in the object
-        assert_eq!(unsafe { (*GcCell::data(*ptr)).value }, 2);
+        {
+            let s = &mut Scope::new(&mut cx);
+            let value = Local::new(s, 0u32);
+            // the holder is old, and nothing records that it now refers to a young object
+            *holder.to_local(s).value.borrow_mut() = Some(value.to_member());
+        }
+        let _ = Scope::new(&mut cx);
+
+        cx.collect_young();
     }
 
     #[test]
-    fn tombstone_nested() {
-        COLLECTED_NODES.with_borrow_mut(|v| v.clear());
+    fn large_objects() {
+        let mut cx = Gc::new(Config::default().stress(false).large_object_threshold(256));
+        let bytes = |cx: &Gc| unsafe {
+            let allocator = &*cx.allocator.get();
+            (allocator.bytes.get(), allocator.large_bytes.get())
+        };
+        let addresses = |cx: &mut Gc, small: &Global<[u8; 64]>, large: &Global<[u8; 1024]>| {
+            let s = &mut Scope::new(cx);
+            (
+                small.to_local(s).to_member().as_raw(),
+                large.to_local(s).to_member().as_raw(),
+            )
+        };
 
-        let cx = &mut Gc::default();
+        let (small, large) = {
+            let s = &mut Scope::new(&mut cx);
+            let small = Local::new(s, [0u8; 64]);
+            let large = Local::new(s, [1u8; 1024]);
+            (Global::new(s, &small), Global::new(s, &large))
+        };
+        // the large object is counted in both
+        let large_bytes = large_mapping::<[u8; 1024]>();
+        assert_eq!(bytes(&cx), (80 + large_bytes, large_bytes));
+
+        let before = addresses(&mut cx, &small, &large);
+        cx.compact();
+        let after = addresses(&mut cx, &small, &large);
+        // the small object is alone in its page, so it stays as well
+        assert_eq!(before, after);
+
+        drop(large);
+        let _ = Scope::new(&mut cx);
+        cx.collect_all();
+        assert_eq!(bytes(&cx), (80, 0));
+    }
+
+    #[test]
+    fn compact_pending_finalizer() {
+        let mut cx = Gc::new(Config::default().stress(false));
+        let finalized = Rc::new(RefCell::new(vec![]));
 
-        let scope0 = &mut Scope::new(cx);
-        let _ = Node::new(scope0, 1);
         {
-            let scope1 = &mut Scope::new(scope0);
-            let _ = Node::new(scope1, 2);
-            {
-                let scope2 = &mut Scope::new(scope1);
-                let _ = Node::new(scope2, 3);
-            }
+            let s = &mut Scope::new(&mut cx);
+            let list = node_chain(s, 2);
+            let finalized = finalized.clone();
+            s.register_finalizer(&list, move |s, node| {
+                let next = project!(s, node.next).unwrap();
+                finalized.borrow_mut().push((node.value, next.value));
+            });
         }
+        let _ = Scope::new(&mut cx);
 
-        scope0.collect();
-
-        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[3]));
+        cx.compact();
+        assert_eq!(*finalized.borrow(), [(1, 2)]);
     }
 
     #[test]
-    fn tombstone_next_block() {
+    fn long_chain() {
+        const N: u32 = 1_000_000;
+
         COLLECTED_NODES.with_borrow_mut(|v| v.clear());
 
-        let cx = &mut Gc::default();
+        let mut cx = Gc::new(Config::default().stress(false).mark_chunk_size(64));
 
-        let outer = &mut Scope::new(cx);
-        let first = Node::new(outer, 1);
+        let s = &mut Scope::new(&mut cx);
         {
-            let inner = &mut Scope::new(outer);
-            for _ in 0..crate::handle::BLOCK_SIZE {
-                let _ = Node::new(inner, 2);
+            let s = &mut Scope::new(s);
+            let first = node_chain(s, N);
+            reset_tombstone(s);
+
+            // only `first` is a root, the rest is reachable through it
+            s.collect_all();
+            assert!(COLLECTED_NODES.with_borrow(|v| v.is_empty()));
+
+            let mut node = LocalMut::new(s, &first);
+            let mut n = 1;
+            while node.follow(|node| &node.next) {
+                n += 1;
             }
+            assert_eq!(n, N);
         }
-        let second = Node::new(outer, 3);
+        reset_tombstone(s);
 
-        // 1 handle apart
-        let distance = (second.as_ptr() as usize - first.as_ptr() as usize) / 8;
-        assert_eq!(distance, 1);
+        s.collect_all();
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v.len(), N as usize));
     }
 
     #[test]
@@ -585,9 +2882,9 @@ mod tests {
                 let three = Node::new(s, 3);
                 let four = Node::new(s, 4);
 
-                node_join(&one, &two);
-                node_join(&two, &three);
-                node_join(&three, &four);
+                node_join(s, &one, &two);
+                node_join(s, &two, &three);
+                node_join(s, &three, &four);
 
                 s.escape(one)
             };
@@ -616,11 +2913,9 @@ mod tests {
 
                 let s = &mut Scope::new(s);
                 let one = root.in_scope(s);
-                unsafe {
-                    let two = one.next.borrow().unwrap().in_scope(s);
-                    let three = two.next.borrow().unwrap().in_scope(s);
-                    node_join(&three, &one);
-                }
+                let two = project!(s, one.next).unwrap();
+                let three = project!(s, two.next).unwrap();
+                node_join(s, &three, &one);
             }
 
             s.collect();
@@ -643,6 +2938,9 @@ mod tests {
 
         drop(cx);
 
-        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[4, 3, 2, 1]));
+        // dropping the `Gc` frees the remaining objects in the order of
+        // their cells, and the nodes were allocated into consecutive cells
+        // of the same page, so they are dropped in allocation order
+        COLLECTED_NODES.with_borrow(|v| assert_eq!(v, &[4, 1, 2, 3]));
     }
 }
@@ -1,9 +1,36 @@
-#[cfg(all(debug_assertions, __verbose_gc))]
+//! Internal logging.
+//!
+//! `trace!` is used for events about individual objects and handles,
+//! `debug!` for the phases of a collection. Both accept an optional list
+//! of fields, which are recorded using their `Debug` impl:
+//!
+//! ```rust,ignore
+//! trace!(ptr = ptr; "alloc");
+//! debug!(freed_objects = n, freed_bytes = bytes; "sweep done");
+//! debug!("start marking");
+//! ```
+//!
+//! With the `tracing` feature, these are emitted as `tracing` events, and with
+//! the `log` feature as `log` records with key-values, both under the `gc` target.
+//! If both are enabled, `tracing` is used. Otherwise they are printed to stdout,
+//! but only in debug builds with `--cfg=__verbose_gc`.
+
+#[cfg(all(
+    debug_assertions,
+    __verbose_gc,
+    not(feature = "tracing"),
+    not(feature = "log")
+))]
 pub fn type_name_of<T>(_: T) -> &'static str {
     std::any::type_name::<T>()
 }
 
-#[cfg(all(debug_assertions, __verbose_gc))]
+#[cfg(all(
+    debug_assertions,
+    __verbose_gc,
+    not(feature = "tracing"),
+    not(feature = "log")
+))]
 macro_rules! __function {
     () => {{
         fn f() {}
@@ -15,13 +42,68 @@ macro_rules! __function {
     }};
 }
 
-macro_rules! debug {
-    ($($tt:tt)+) => {
-        #[cfg(all(debug_assertions, __verbose_gc))] {
+macro_rules! __event {
+    ($log:ident, $tracing:ident, $($key:ident = $value:expr),+ ; $($fmt:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::event!(
+            target: "gc",
+            ::tracing::Level::$tracing,
+            $($key = ?$value,)+
+            $($fmt)+
+        );
+
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        ::log::log!(
+            target: "gc",
+            ::log::Level::$log,
+            $($key:? = $value),+;
+            $($fmt)+
+        );
+
+        #[cfg(all(
+            debug_assertions,
+            __verbose_gc,
+            not(feature = "tracing"),
+            not(feature = "log")
+        ))] {
             print!("[");
             print!("{}", __function!());
             print!("]: ");
-            println!($($tt)+);
+            print!($($fmt)+);
+            $(print!(", {}={:?}", stringify!($key), $value);)+
+            println!();
         }
-    }
+    };
+
+    ($log:ident, $tracing:ident, $($fmt:tt)+) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::event!(target: "gc", ::tracing::Level::$tracing, $($fmt)+);
+
+        #[cfg(all(feature = "log", not(feature = "tracing")))]
+        ::log::log!(target: "gc", ::log::Level::$log, $($fmt)+);
+
+        #[cfg(all(
+            debug_assertions,
+            __verbose_gc,
+            not(feature = "tracing"),
+            not(feature = "log")
+        ))] {
+            print!("[");
+            print!("{}", __function!());
+            print!("]: ");
+            println!($($fmt)+);
+        }
+    };
+}
+
+macro_rules! trace {
+    ($($tt:tt)+) => {
+        __event!(Trace, TRACE, $($tt)+)
+    };
+}
+
+macro_rules! debug {
+    ($($tt:tt)+) => {
+        __event!(Debug, DEBUG, $($tt)+)
+    };
 }
@@ -0,0 +1,326 @@
+//! Page-based object storage.
+//!
+//! Objects are stored in pages of `PAGE_SIZE` bytes, each of which is divided
+//! into cells of a single size class. Every page keeps its own list of free
+//! cells, so allocation is usually just popping from a free list, and sweeping
+//! walks the cells of each page instead of a list of objects.
+//!
+//! Objects which do not fit into any size class, or which need a greater
+//! alignment than cells have, are stored in the large object space instead.
+//!
+//! Pages are aligned to their size, and zeroed when they are allocated, so
+//! the page and cell which any address falls into can be found, and a cell
+//! which does not hold an object is recognized by its null vtable pointer.
+
+use crate::alloc::Data;
+use crate::alloc::GcCell;
+use std::alloc::alloc_zeroed;
+use std::alloc::dealloc;
+use std::alloc::handle_alloc_error;
+use std::alloc::Layout;
+use std::collections::HashMap;
+use std::ptr::null;
+use std::ptr::null_mut;
+
+/// Size of the pages which hold objects of a size class.
+pub(crate) const PAGE_SIZE: usize = 16 * 1024;
+
+/// Alignment of every cell.
+const CELL_ALIGN: usize = 16;
+
+/// Size of the largest size class.
+pub(crate) const MAX_CELL_SIZE: usize = SIZE_CLASSES[SIZE_CLASSES.len() - 1];
+
+/// Cell sizes of all size classes.
+///
+/// Steps of 16 bytes up to 128, and then 4 steps per power of two, so
+/// that at most 25% of a cell is wasted.
+const SIZE_CLASSES: [usize; 24] = [
+    16, 32, 48, 64, 80, 96, 112, 128, //
+    160, 192, 224, 256, //
+    320, 384, 448, 512, //
+    640, 768, 896, 1024, //
+    1280, 1536, 1792, 2048,
+];
+
+/// Index of the smallest size class which fits `layout`.
+fn size_class(layout: Layout) -> Option<usize> {
+    if layout.align() > CELL_ALIGN {
+        return None;
+    }
+    let class = SIZE_CLASSES.partition_point(|&size| size < layout.size());
+    (class < SIZE_CLASSES.len()).then_some(class)
+}
+
+/// Size of the cells which objects with `layout` are stored in,
+/// or `None` if they do not fit into any size class.
+pub(crate) fn cell_size(layout: Layout) -> Option<usize> {
+    size_class(layout).map(|class| SIZE_CLASSES[class])
+}
+
+/// A cell which does not hold an object.
+///
+/// `vt` is at the same offset as in `GcHeader`, and is always null,
+/// which is how free cells are told apart from objects.
+#[repr(C)]
+struct FreeCell {
+    vt: *const (),
+    next: *mut FreeCell,
+}
+
+pub(crate) struct Page {
+    base: *mut u8,
+    layout: Layout,
+    cell_size: usize,
+    cells: usize,
+
+    /// Cells at or above this index have never been used.
+    bump: usize,
+
+    /// Cells below `bump` which were freed.
+    free: *mut FreeCell,
+
+    /// Number of cells which hold an object.
+    live: usize,
+
+    /// Whether an object was allocated in this page since the last minor collection.
+    pub(crate) has_young: bool,
+}
+
+impl Page {
+    fn new(cell_size: usize) -> Self {
+        let cells = PAGE_SIZE / cell_size;
+        let layout = Layout::from_size_align(cell_size * cells, PAGE_SIZE).unwrap();
+        let base = unsafe { alloc_zeroed(layout) };
+        if base.is_null() {
+            handle_alloc_error(layout);
+        }
+        debug!(ptr = base, cell_size = cell_size; "new page");
+
+        Self {
+            base,
+            layout,
+            cell_size,
+            cells,
+            bump: 0,
+            free: null_mut(),
+            live: 0,
+            has_young: false,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn cell_size(&self) -> usize {
+        self.cell_size
+    }
+
+    #[inline]
+    fn cell(&self, index: usize) -> *mut u8 {
+        unsafe { self.base.add(index * self.cell_size) }
+    }
+
+    #[inline]
+    fn is_free(cell: *mut u8) -> bool {
+        unsafe { (*(cell as *const FreeCell)).vt.is_null() }
+    }
+
+    /// Find a free cell, or return `None` if the page is full.
+    #[inline]
+    fn alloc(&mut self) -> Option<*mut u8> {
+        let cell = if !self.free.is_null() {
+            let cell = self.free;
+            self.free = unsafe { (*cell).next };
+            cell as *mut u8
+        } else if self.bump < self.cells {
+            self.bump += 1;
+            self.cell(self.bump - 1)
+        } else {
+            return None;
+        };
+
+        self.live += 1;
+        self.has_young = true;
+        Some(cell)
+    }
+
+    /// Iterate over all cells which hold an object.
+    pub(crate) fn objects(&self) -> impl Iterator<Item = *mut GcCell<Data>> + '_ {
+        (0..self.bump)
+            .map(|index| self.cell(index))
+            .filter(|&cell| !Self::is_free(cell))
+            .map(|cell| cell as *mut GcCell<Data>)
+    }
+
+    /// Free every object for which `f` returns `false`.
+    ///
+    /// Returns the number of freed objects.
+    ///
+    /// ## Safety
+    /// The objects must not be used after they are freed.
+    pub(crate) unsafe fn retain(&mut self, mut f: impl FnMut(*mut GcCell<Data>) -> bool) -> usize {
+        let mut freed = 0;
+        for index in 0..self.bump {
+            let cell = self.cell(index);
+            if Self::is_free(cell) || f(cell as *mut GcCell<Data>) {
+                continue;
+            }
+
+            GcCell::drop_data(cell as *mut GcCell<Data>);
+            let cell = cell as *mut FreeCell;
+            cell.write(FreeCell {
+                vt: null(),
+                next: self.free,
+            });
+            self.free = cell;
+            freed += 1;
+        }
+        self.live -= freed;
+        freed
+    }
+}
+
+impl Drop for Page {
+    /// Objects are not dropped, because they may have been moved elsewhere.
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base, self.layout) }
+    }
+}
+
+#[derive(Default)]
+struct SizeClass {
+    pages: Vec<Page>,
+
+    /// Pages below this index are full.
+    cursor: usize,
+}
+
+impl SizeClass {
+    fn alloc(&mut self, cell_size: usize) -> *mut u8 {
+        while let Some(page) = self.pages.get_mut(self.cursor) {
+            if let Some(cell) = page.alloc() {
+                return cell;
+            }
+            self.cursor += 1;
+        }
+
+        let mut page = Page::new(cell_size);
+        let cell = page.alloc().unwrap();
+        self.pages.push(page);
+        cell
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Heap {
+    /// Pages of each size class, in the same order as `SIZE_CLASSES`.
+    classes: [SizeClass; SIZE_CLASSES.len()],
+
+    /// Cell size of every page, by the address of the page.
+    cell_sizes: HashMap<usize, usize>,
+}
+
+impl Heap {
+    /// Find a free cell for an object with `layout`.
+    ///
+    /// Returns the cell and its size, or `None` if the object
+    /// does not fit into any size class.
+    pub(crate) fn alloc(&mut self, layout: Layout) -> Option<(*mut u8, usize)> {
+        let index = size_class(layout)?;
+        let cell_size = SIZE_CLASSES[index];
+        let class = &mut self.classes[index];
+        let pages = class.pages.len();
+        let cell = class.alloc(cell_size);
+        if class.pages.len() > pages {
+            let base = class.pages[pages].base as usize;
+            self.cell_sizes.insert(base, cell_size);
+        }
+        Some((cell, cell_size))
+    }
+
+    /// Whether `ptr` points to an object in one of the pages.
+    pub(crate) fn contains(&self, ptr: *mut GcCell<Data>) -> bool {
+        let addr = ptr as usize;
+        let base = addr & !(PAGE_SIZE - 1);
+        let Some(&cell_size) = self.cell_sizes.get(&base) else {
+            return false;
+        };
+        let offset = addr - base;
+        offset.is_multiple_of(cell_size)
+            && offset / cell_size < PAGE_SIZE / cell_size
+            && !Page::is_free(ptr as *mut u8)
+    }
+
+    pub(crate) fn pages(&self) -> impl Iterator<Item = &Page> {
+        self.classes.iter().flat_map(|class| class.pages.iter())
+    }
+
+    pub(crate) fn pages_mut(&mut self) -> impl Iterator<Item = &mut Page> {
+        self.classes
+            .iter_mut()
+            .flat_map(|class| class.pages.iter_mut())
+    }
+
+    /// Iterate over all objects.
+    pub(crate) fn objects(&self) -> impl Iterator<Item = *mut GcCell<Data>> + '_ {
+        self.pages().flat_map(|page| page.objects())
+    }
+
+    /// Release all pages which no longer hold any objects.
+    ///
+    /// Must be called after sweeping, so that allocation
+    /// can find the cells freed in the other pages.
+    pub(crate) fn free_empty_pages(&mut self) {
+        for class in self.classes.iter_mut() {
+            class.pages.retain(|page| {
+                if page.live == 0 {
+                    self.cell_sizes.remove(&(page.base as usize));
+                }
+                page.live > 0
+            });
+            class.cursor = 0;
+        }
+    }
+
+    /// Move objects of all size classes into as few pages as possible.
+    ///
+    /// The densest pages of each size class are kept, and the objects of all
+    /// other pages are moved into their free cells. The kept pages have room
+    /// for every object of the class, so no new pages are allocated, and only
+    /// the objects of sparse pages are copied.
+    ///
+    /// `relocate` is called with the old and new location of each object,
+    /// after its contents were copied.
+    ///
+    /// Returns the evacuated pages, which still have to be freed.
+    ///
+    /// ## Safety
+    /// All references to the moved objects must be updated.
+    pub(crate) unsafe fn compact(
+        &mut self,
+        mut relocate: impl FnMut(*mut GcCell<Data>, *mut GcCell<Data>),
+    ) -> Vec<Page> {
+        let mut old_pages = vec![];
+        for (class, &cell_size) in self.classes.iter_mut().zip(SIZE_CLASSES.iter()) {
+            let live = class.pages.iter().map(|page| page.live).sum::<usize>();
+            let needed = live.div_ceil(PAGE_SIZE / cell_size);
+            if needed >= class.pages.len() {
+                continue;
+            }
+
+            class.pages.sort_by_key(|page| std::cmp::Reverse(page.live));
+            let sparse = class.pages.split_off(needed);
+            class.cursor = 0;
+            for object in sparse.iter().flat_map(|page| page.objects()) {
+                let new = class.alloc(cell_size);
+                std::ptr::copy_nonoverlapping(object as *const u8, new, cell_size);
+                relocate(object, new as *mut GcCell<Data>);
+            }
+            debug_assert_eq!(class.pages.len(), needed, "compaction allocated a page");
+            for page in sparse.iter() {
+                self.cell_sizes.remove(&(page.base as usize));
+            }
+            old_pages.extend(sparse);
+        }
+        old_pages
+    }
+}
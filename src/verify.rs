@@ -0,0 +1,116 @@
+//! Heap verification.
+//!
+//! With `Config::verify`, the object graph is walked from the roots after
+//! every collection, and every reference is checked to point at an object
+//! which is still allocated. A reference to a freed object means that it was
+//! not found by marking, usually because of a missing write barrier, or a
+//! `Trace` implementation which does not trace a field.
+//!
+//! This finds such bugs right after the collection which introduced them,
+//! instead of whenever the freed memory happens to be reused.
+
+use crate::alloc::Allocator;
+use crate::alloc::Data;
+use crate::alloc::GcCell;
+use crate::gc::Tracer;
+use crate::handle::ScopeData;
+use std::collections::HashSet;
+use std::ptr::null_mut;
+
+type OpaquePtr = *mut GcCell<Data>;
+
+pub(crate) struct Verifier {
+    /// All objects which are currently allocated.
+    live: HashSet<OpaquePtr>,
+
+    /// Objects which were already reached.
+    visited: HashSet<OpaquePtr>,
+
+    /// Objects which were reached, but whose fields have not been checked yet.
+    stack: Vec<OpaquePtr>,
+
+    /// The object whose fields are being traced.
+    current: OpaquePtr,
+}
+
+impl Verifier {
+    /// Check a strong reference from the current object, and follow it.
+    pub(crate) unsafe fn edge(&mut self, ptr: OpaquePtr) {
+        if !self.live.contains(&ptr) {
+            panic!(
+                "heap verification failed: `{}` at {:p} refers to {ptr:p}, which is not a live object",
+                GcCell::type_name(self.current),
+                self.current,
+            );
+        }
+        if self.visited.insert(ptr) {
+            self.stack.push(ptr);
+        }
+    }
+
+    /// Check a weak reference from the current object, which must
+    /// have been cleared if the object it refers to was freed.
+    pub(crate) unsafe fn weak_edge(&mut self, ptr: OpaquePtr) {
+        if !ptr.is_null() {
+            self.edge(ptr);
+        }
+    }
+
+    /// Check a reference from outside of the heap, and follow it.
+    fn root(&mut self, ptr: OpaquePtr, what: &str) {
+        if ptr.is_null() {
+            return;
+        }
+        if !self.live.contains(&ptr) {
+            panic!(
+                "heap verification failed: {what} refers to {ptr:p}, which is not a live object"
+            );
+        }
+        if self.visited.insert(ptr) {
+            self.stack.push(ptr);
+        }
+    }
+}
+
+/// Check that every reference reachable from the roots points at a live object.
+///
+/// Panics if one does not.
+///
+/// ## Safety
+/// Must not be called during marking.
+pub(crate) unsafe fn verify(scope_data: *mut ScopeData, allocator: *mut Allocator) {
+    debug!("verify heap");
+
+    let scope_data = &mut *scope_data;
+    let allocator = &*allocator;
+    let heap = &*allocator.heap.get();
+    let large = &*allocator.large.get();
+
+    let mut verifier = Verifier {
+        live: heap.objects().chain(large.objects()).collect(),
+        visited: HashSet::new(),
+        stack: vec![],
+        current: null_mut(),
+    };
+
+    for ptr in scope_data.iter() {
+        verifier.root(ptr, "a handle");
+    }
+    for slot in scope_data.globals() {
+        verifier.root(slot.get(), "a `Global` handle");
+    }
+    for slot in scope_data.weaks() {
+        verifier.root(slot.get(), "a `Weak` handle");
+    }
+
+    let finalizers = &*allocator.finalizers.get();
+    let queue = &*allocator.finalization_queue.get();
+    for finalizer in finalizers.iter().chain(queue.iter()) {
+        verifier.root(finalizer.cell, "a finalizer");
+    }
+
+    while let Some(ptr) = verifier.stack.pop() {
+        verifier.current = ptr;
+        GcCell::trace(ptr, &mut Tracer::verify(&mut verifier));
+    }
+}
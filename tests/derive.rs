@@ -1,6 +1,293 @@
 #![allow(dead_code)]
 
+use gc::Config;
+use gc::Gc;
+use gc::Local;
+use gc::Member;
+use gc::Scope;
+use gc::Trace;
+use gc::Weak;
+
 #[derive(gc::Trace)]
 struct Foo {
     v: Vec<u8>,
 }
+
+#[derive(Trace)]
+struct Leaf {
+    value: u32,
+}
+
+/// Allocate a `Leaf` which is only reachable through the object
+/// returned by `f`, collect, and check that the leaf is still alive.
+fn assert_traced<T: Trace>(f: impl for<'a> FnOnce(&mut Scope<'a>, Member<Leaf>) -> T) {
+    let mut cx = Gc::new(Config::default().stress(false));
+    let s = &mut Scope::new(&mut cx);
+
+    let (weak, _object) = {
+        let s = &mut gc::EscapeScope::new(s);
+        let leaf = Local::new(s, Leaf { value: 100 });
+        let weak = Weak::new(s, &leaf);
+        let object = f(s, leaf.to_member());
+        let object = Local::new(s, object);
+        (weak, s.escape(object))
+    };
+    // drop the handles of the inner scope
+    let _ = Scope::new(s);
+
+    s.collect_all();
+
+    let leaf = weak.upgrade(s).expect("leaf was not traced");
+    assert_eq!(leaf.value, 100);
+}
+
+#[derive(Trace)]
+struct Named {
+    leaf: Member<Leaf>,
+    value: u32,
+}
+
+#[test]
+fn named_struct() {
+    assert_traced(|_, leaf| Named { leaf, value: 0 });
+}
+
+#[derive(Trace)]
+struct Tuple(u32, Member<Leaf>);
+
+#[test]
+fn tuple_struct() {
+    assert_traced(|_, leaf| Tuple(0, leaf));
+}
+
+#[derive(Trace)]
+struct Unit;
+
+#[test]
+fn unit_struct() {
+    assert_traced(|_, leaf| (Unit, leaf));
+}
+
+#[derive(Trace)]
+enum Value {
+    Nil,
+    Int(i64),
+    Pair(Member<Leaf>, Member<Leaf>),
+    Object { leaf: Member<Leaf>, len: usize },
+}
+
+#[test]
+fn enum_unit_variant() {
+    assert_traced(|_, leaf| (Value::Nil, leaf));
+}
+
+#[test]
+fn enum_tuple_variant() {
+    assert_traced(|_, leaf| Value::Pair(leaf.clone(), leaf));
+}
+
+#[test]
+fn enum_named_variant() {
+    assert_traced(|_, leaf| Value::Object { leaf, len: 0 });
+}
+
+/// A field with the name of the parameter of `Trace::trace`.
+#[derive(Trace)]
+enum Shadowing {
+    A { tracer: Member<Leaf> },
+}
+
+#[test]
+fn enum_field_named_tracer() {
+    assert_traced(|_, leaf| Shadowing::A { tracer: leaf });
+}
+
+#[derive(Trace)]
+enum Empty {}
+
+#[derive(Trace)]
+struct Generic<T> {
+    value: T,
+}
+
+#[test]
+fn generic_struct() {
+    assert_traced(|_, leaf| Generic { value: leaf });
+}
+
+#[derive(Trace)]
+struct GenericWhere<T, U>(T, U)
+where
+    T: Clone,
+    U: Default;
+
+#[test]
+fn generic_where_clause() {
+    assert_traced(|_, leaf| GenericWhere(leaf, 0u32));
+}
+
+#[derive(Trace)]
+enum GenericEnum<T: Clone> {
+    None,
+    Some(T),
+}
+
+#[test]
+fn generic_enum() {
+    assert_traced(|_, leaf| GenericEnum::Some(leaf));
+}
+
+/// A type from some other crate, which does not implement `Trace`.
+struct Foreign {
+    value: u32,
+}
+
+#[derive(Trace)]
+struct Skipped {
+    leaf: Member<Leaf>,
+    #[trace(skip)]
+    path: std::path::PathBuf,
+    #[trace(skip)]
+    durations: Vec<std::time::Duration>,
+}
+
+#[test]
+fn skip_field() {
+    assert_traced(|_, leaf| Skipped {
+        leaf,
+        path: std::path::PathBuf::from("/"),
+        durations: vec![],
+    });
+}
+
+/// `NoTrace` can't be implemented for a type from another crate,
+/// but it can be for a local newtype around one.
+struct ForeignNewtype(Foreign);
+
+unsafe impl gc::NoTrace for ForeignNewtype {}
+
+#[derive(Trace)]
+struct SkippedNewtype {
+    leaf: Member<Leaf>,
+    #[trace(skip)]
+    foreign: ForeignNewtype,
+}
+
+#[test]
+fn skip_newtype_field() {
+    assert_traced(|_, leaf| SkippedNewtype {
+        leaf,
+        foreign: ForeignNewtype(Foreign { value: 0 }),
+    });
+}
+
+#[derive(Trace)]
+struct Ignored {
+    leaf: Member<Leaf>,
+    #[trace(unsafe_ignore)]
+    foreign: Foreign,
+}
+
+#[test]
+fn unsafe_ignore_field() {
+    assert_traced(|_, leaf| Ignored {
+        leaf,
+        foreign: Foreign { value: 0 },
+    });
+}
+
+/// Stores a reference in a type which can't implement `Trace`.
+struct Wrapper(Member<Leaf>);
+
+unsafe fn trace_wrapper(wrapper: &Wrapper, tracer: &mut gc::Tracer) {
+    wrapper.0.trace(tracer);
+}
+
+#[derive(Trace)]
+struct Custom {
+    #[trace(with = trace_wrapper)]
+    wrapper: Wrapper,
+}
+
+#[test]
+fn custom_trace_fn() {
+    assert_traced(|_, leaf| Custom {
+        wrapper: Wrapper(leaf),
+    });
+}
+
+#[derive(Trace)]
+enum AttrEnum {
+    Named {
+        #[trace(skip)]
+        name: String,
+        #[trace(with = trace_wrapper)]
+        wrapper: Wrapper,
+    },
+    Unnamed(#[trace(unsafe_ignore)] Foreign, Member<Leaf>),
+}
+
+#[test]
+fn enum_field_attrs() {
+    assert_traced(|_, leaf| AttrEnum::Named {
+        name: String::new(),
+        wrapper: Wrapper(leaf),
+    });
+    assert_traced(|_, leaf| AttrEnum::Unnamed(Foreign { value: 0 }, leaf));
+}
+
+thread_local! {
+    static FINALIZED: std::cell::Cell<u32> = const { std::cell::Cell::new(0) };
+}
+
+#[derive(Trace)]
+#[trace(finalize)]
+struct Finalized {
+    leaf: Member<Leaf>,
+    value: u32,
+}
+
+impl gc::Finalize for Finalized {
+    fn finalize(&mut self) {
+        FINALIZED.set(FINALIZED.get() + self.value);
+    }
+}
+
+#[test]
+fn finalize() {
+    let mut cx = Gc::new(Config::default().stress(false));
+    let s = &mut Scope::new(&mut cx);
+
+    {
+        let s = &mut Scope::new(s);
+        let leaf = Local::new(s, Leaf { value: 0 });
+        let leaf = leaf.to_member();
+        let _ = Local::new(s, Finalized { leaf, value: 10 });
+        s.collect_all();
+        assert_eq!(FINALIZED.get(), 0);
+    }
+    // drop the handles of the inner scope
+    let _ = Scope::new(s);
+
+    s.collect_all();
+    assert_eq!(FINALIZED.get(), 10);
+}
+
+#[derive(Trace)]
+#[trace(unsafe_drop)]
+struct UnsafeDrop {
+    value: Vec<u32>,
+}
+
+impl Drop for UnsafeDrop {
+    fn drop(&mut self) {
+        self.value.clear();
+    }
+}
+
+#[test]
+fn unsafe_drop() {
+    let mut cx = Gc::new(Config::default().stress(false));
+    let s = &mut Scope::new(&mut cx);
+    let _ = Local::new(s, UnsafeDrop { value: vec![0] });
+}